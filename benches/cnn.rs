@@ -0,0 +1,149 @@
+#![feature(test)]
+
+extern crate test;
+extern crate waifu2x_hsa;
+
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use test::Bencher;
+use waifu2x_hsa::{cnn, image, PerfStatus, UpscalerBuilder};
+use waifu2x_hsa::cnn::WinogradTile;
+use waifu2x_hsa::image::{Image, ColorSpace};
+use waifu2x_hsa::model::{self, Model, Layer};
+
+// Layer shapes of the waifu2x Y models (7 3x3 layers, 1 -> 32 -> ... -> 128 -> 1)
+// on a 64x64 output tile.
+const PLANES: [u32; 8] = [1, 32, 32, 64, 64, 128, 128, 1];
+const SIZE: usize = 64;
+
+// Benchmarks report `b.bytes` = floating point operations per iteration, so the
+// MB/s column of `cargo bench` reads as MFLOPS (compare with the README table).
+// For the Winograd backends these are the operations of the direct convolution.
+
+fn lcg(state: &mut u64) -> f32 {
+    *state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    ((*state >> 40) as f32 / (1 << 24) as f32) - 0.5
+}
+
+fn bench_model() -> Model {
+    let mut state = 1;
+    let mut model = Vec::new();
+    for i in 0..PLANES.len() - 1 {
+        let (num_in, num_out) = (PLANES[i], PLANES[i + 1]);
+        let scale = 2.0 / ((num_in * 9) as f32).sqrt();
+        model.push(Layer {
+            nInputPlane: num_in,
+            nOutputPlane: num_out,
+            kW: 3,
+            kH: 3,
+            bias: (0..num_out).map(|_| lcg(&mut state) * 0.2).collect(),
+            weight: (0..num_out).map(|_| (0..num_in).map(|_| (0..3).map(|_| {
+                (0..3).map(|_| lcg(&mut state) * scale).collect()
+            }).collect()).collect()).collect(),
+        });
+    }
+    model::validate_model(&model).unwrap();
+    model
+}
+
+fn bench_image(model: &Model) -> Image {
+    let (pad_x, pad_y) = model::padding(model);
+    let mut img = Image::new(SIZE + pad_x * 2, SIZE + pad_y * 2, 1, ColorSpace::Gray);
+    let mut state = 2;
+    for v in img.data[0].iter_mut() {
+        *v = lcg(&mut state) + 0.5;
+    }
+    img
+}
+
+fn run<F: FnMut(Image) -> Image>(b: &mut Bencher, mut filter: F) {
+    let model = bench_model();
+    let img = bench_image(&model);
+    b.bytes = cnn::flops(&model, img.width, img.height);
+    b.iter(|| filter(img.clone()));
+}
+
+#[bench]
+fn cpu2(b: &mut Bencher) {
+    let model = bench_model();
+    run(b, |img| cnn::filter_cpu2(img, &model, &mut PerfStatus::new()));
+}
+
+#[bench]
+fn cpu2_4threads(b: &mut Bencher) {
+    let model = bench_model();
+    run(b, |img| cnn::filter_cpu2_mt(img, &model, 4, &mut PerfStatus::new()));
+}
+
+#[bench]
+fn gemm(b: &mut Bencher) {
+    let model = bench_model();
+    let weights: Vec<_> = model.iter().map(|l| Arc::new(cnn::gemm_pack_weights(l))).collect();
+    run(b, |img| cnn::filter_gemm(img, &model, &weights, 1, &mut PerfStatus::new()));
+}
+
+#[bench]
+fn gemm_4threads(b: &mut Bencher) {
+    let model = bench_model();
+    let weights: Vec<_> = model.iter().map(|l| Arc::new(cnn::gemm_pack_weights(l))).collect();
+    run(b, |img| cnn::filter_gemm(img, &model, &weights, 4, &mut PerfStatus::new()));
+}
+
+#[bench]
+fn winograd2(b: &mut Bencher) {
+    let model = bench_model();
+    let weights: Vec<_> = model.iter().map(|l| Arc::new(cnn::winograd_pack_weights(l, WinogradTile::F2x2))).collect();
+    run(b, |img| cnn::filter_winograd(img, &model, &weights, 1, &mut PerfStatus::new()));
+}
+
+#[bench]
+fn winograd4(b: &mut Bencher) {
+    let model = bench_model();
+    let weights: Vec<_> = model.iter().map(|l| Arc::new(cnn::winograd_pack_weights(l, WinogradTile::F4x4))).collect();
+    run(b, |img| cnn::filter_winograd(img, &model, &weights, 1, &mut PerfStatus::new()));
+}
+
+// The upscale_* benchmarks reproduce the README setup: scale2.0x of miku_small.png
+// with the anime_style_art models, single threaded. They look for the files in
+// $WAIFU2X_BENCH_IMAGE and $WAIFU2X_BENCH_MODEL_DIR (default: images/miku_small.png
+// and models/anime_style_art from a waifu2x checkout next to this one) and do
+// nothing if they are missing. time[ms] is ns/iter / 1e6, GFLOPS is MB/s / 1000.
+
+fn bench_path(var: &str, default: &str) -> PathBuf {
+    env::var_os(var).map(PathBuf::from).unwrap_or_else(|| PathBuf::from("../waifu2x").join(default))
+}
+
+fn run_upscale(b: &mut Bencher, backend: &str) {
+    let image_path = bench_path("WAIFU2X_BENCH_IMAGE", "images/miku_small.png");
+    let model_dir = bench_path("WAIFU2X_BENCH_MODEL_DIR", "models/anime_style_art");
+    if !image_path.is_file() || !model_dir.is_dir() {
+        println!("skipped: {} or {} not found", image_path.display(), model_dir.display());
+        return;
+    }
+    let img = image::load(&image_path).unwrap();
+    let upscaler = UpscalerBuilder::new(&model_dir).scale(2.0).backend(backend).threads(1).build().unwrap();
+    b.bytes = upscaler.upscale(img.clone()).unwrap().perf.cnn_flo;
+    b.iter(|| upscaler.upscale(img.clone()).unwrap());
+}
+
+#[bench]
+fn upscale_cpu2(b: &mut Bencher) {
+    run_upscale(b, "cpu2");
+}
+
+#[bench]
+fn upscale_gemm(b: &mut Bencher) {
+    run_upscale(b, "gemm");
+}
+
+#[bench]
+fn upscale_winograd2(b: &mut Bencher) {
+    run_upscale(b, "winograd2");
+}
+
+#[bench]
+fn upscale_winograd4(b: &mut Bencher) {
+    run_upscale(b, "winograd4");
+}
@@ -0,0 +1,267 @@
+use std;
+use std::sync::Arc;
+
+use cnn::{self, WinogradTile};
+use image::Image;
+use model::{Model, LayerWeights, PackedModel};
+use super::PerfStatus;
+
+/// A CNN implementation the filter pipeline can run models on.
+pub trait Backend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Converts the model into the representation this backend runs on.
+    fn prepare(&self, model: Model) -> Box<PreparedModel>;
+
+    /// Like prepare, for a model whose weights are already packed
+    /// (e.g. loaded from a binary model file).
+    fn prepare_packed(&self, model: PackedModel) -> Box<PreparedModel> {
+        self.prepare(model.into_model())
+    }
+}
+
+/// A model prepared by a Backend.
+pub trait PreparedModel: Send + Sync {
+    fn model(&self) -> &Model;
+
+    /// Runs the whole layer stack on a padded image.
+    fn filter(&self, img: Image, perf: &mut PerfStatus) -> Image;
+
+    /// Floating point operations needed to filter a `width` x `height` padded image.
+    fn flops(&self, width: usize, height: usize) -> u64 {
+        cnn::flops(self.model(), width, height)
+    }
+
+    /// Bytes of working memory needed to filter a `width` x `height` padded image.
+    fn buffer_size(&self, width: usize, height: usize) -> usize;
+}
+
+pub struct BackendConfig {
+    pub threads: usize,
+}
+
+struct BackendEntry {
+    name: &'static str,
+    description: &'static str,
+    create: fn(&BackendConfig) -> Box<Backend>,
+}
+
+// Backends which need optional dependencies (e.g. HSA, OpenCL) are
+// appended here behind `#[cfg(feature = "...")]`.
+fn registry() -> Vec<BackendEntry> {
+    vec![
+        BackendEntry {
+            name: "cpu1",
+            description: "reference implementation (single thread)",
+            create: create_cpu1,
+        },
+        BackendEntry {
+            name: "cpu2",
+            description: "optimized implementation (multithreaded)",
+            create: create_cpu2,
+        },
+        BackendEntry {
+            name: "gemm",
+            description: "im2col + blocked matrix multiply (multithreaded)",
+            create: create_gemm,
+        },
+        BackendEntry {
+            name: "winograd2",
+            description: "Winograd F(2x2, 3x3) (multithreaded)",
+            create: create_winograd2,
+        },
+        BackendEntry {
+            name: "winograd4",
+            description: "Winograd F(4x4, 3x3) (multithreaded)",
+            create: create_winograd4,
+        },
+    ]
+}
+
+pub const DEFAULT_BACKEND: &'static str = "cpu2";
+
+/// Returns (name, description) of every available backend.
+pub fn list() -> Vec<(&'static str, &'static str)> {
+    registry().iter().map(|e| (e.name, e.description)).collect()
+}
+
+pub fn create(name: &str, config: &BackendConfig) -> Option<Box<Backend>> {
+    registry().into_iter().find(|e| e.name == name).map(|e| (e.create)(config))
+}
+
+fn create_cpu1(_: &BackendConfig) -> Box<Backend> {
+    Box::new(Cpu1Backend)
+}
+
+fn create_cpu2(config: &BackendConfig) -> Box<Backend> {
+    Box::new(Cpu2Backend { threads: std::cmp::max(1, config.threads) })
+}
+
+fn create_gemm(config: &BackendConfig) -> Box<Backend> {
+    Box::new(GemmBackend { threads: std::cmp::max(1, config.threads) })
+}
+
+fn create_winograd2(config: &BackendConfig) -> Box<Backend> {
+    Box::new(WinogradBackend { tile: WinogradTile::F2x2, threads: std::cmp::max(1, config.threads) })
+}
+
+fn create_winograd4(config: &BackendConfig) -> Box<Backend> {
+    Box::new(WinogradBackend { tile: WinogradTile::F4x4, threads: std::cmp::max(1, config.threads) })
+}
+
+/// cnn::filter_cpu1
+pub struct Cpu1Backend;
+
+struct Cpu1Model {
+    model: Model,
+}
+
+impl Backend for Cpu1Backend {
+    fn name(&self) -> &'static str {
+        "cpu1"
+    }
+
+    fn prepare(&self, model: Model) -> Box<PreparedModel> {
+        Box::new(Cpu1Model { model: model })
+    }
+}
+
+impl PreparedModel for Cpu1Model {
+    fn model(&self) -> &Model {
+        &self.model
+    }
+
+    fn filter(&self, img: Image, perf: &mut PerfStatus) -> Image {
+        cnn::filter_cpu1(img, &self.model, perf)
+    }
+
+    fn buffer_size(&self, width: usize, height: usize) -> usize {
+        let mut max_planes: usize = 1;
+        for layer in self.model.iter() {
+            max_planes = std::cmp::max(max_planes,
+                                       (layer.nInputPlane + layer.nOutputPlane) as usize);
+        }
+        max_planes * width * height * std::mem::size_of::<f32>()
+    }
+}
+
+/// cnn::filter_cpu2 / cnn::filter_cpu2_mt
+pub struct Cpu2Backend {
+    pub threads: usize,
+}
+
+struct Cpu2Model {
+    model: Model,
+    weights: Vec<LayerWeights>,
+    threads: usize,
+}
+
+impl Backend for Cpu2Backend {
+    fn name(&self) -> &'static str {
+        "cpu2"
+    }
+
+    fn prepare(&self, model: Model) -> Box<PreparedModel> {
+        self.prepare_packed(PackedModel::new(model))
+    }
+
+    fn prepare_packed(&self, model: PackedModel) -> Box<PreparedModel> {
+        Box::new(Cpu2Model { model: model.model, weights: model.weights, threads: self.threads })
+    }
+}
+
+impl PreparedModel for Cpu2Model {
+    fn model(&self) -> &Model {
+        &self.model
+    }
+
+    fn filter(&self, img: Image, perf: &mut PerfStatus) -> Image {
+        if self.threads > 1 {
+            cnn::filter_cpu2_mt_packed(img, &self.model, &self.weights, self.threads, perf)
+        } else {
+            cnn::filter_cpu2_packed(img, &self.model, &self.weights, perf)
+        }
+    }
+
+    fn buffer_size(&self, width: usize, height: usize) -> usize {
+        cnn::filter_cpu2_buffer_size(width, height, &self.model, self.threads)
+    }
+}
+
+/// cnn::filter_gemm
+pub struct GemmBackend {
+    pub threads: usize,
+}
+
+struct GemmModel {
+    model: Model,
+    weights: Vec<Arc<cnn::GemmWeights>>,
+    threads: usize,
+}
+
+impl Backend for GemmBackend {
+    fn name(&self) -> &'static str {
+        "gemm"
+    }
+
+    fn prepare(&self, model: Model) -> Box<PreparedModel> {
+        let weights = model.iter().map(|l| Arc::new(cnn::gemm_pack_weights(l))).collect();
+        Box::new(GemmModel { model: model, weights: weights, threads: self.threads })
+    }
+}
+
+impl PreparedModel for GemmModel {
+    fn model(&self) -> &Model {
+        &self.model
+    }
+
+    fn filter(&self, img: Image, perf: &mut PerfStatus) -> Image {
+        cnn::filter_gemm(img, &self.model, &self.weights, self.threads, perf)
+    }
+
+    fn buffer_size(&self, width: usize, height: usize) -> usize {
+        cnn::filter_gemm_buffer_size(width, height, &self.model, self.threads)
+    }
+}
+
+/// cnn::filter_winograd; the weights are transformed once in prepare,
+/// layers other than 3x3 run on the cpu2 kernels
+pub struct WinogradBackend {
+    pub tile: WinogradTile,
+    pub threads: usize,
+}
+
+struct WinogradModel {
+    model: Model,
+    tile: WinogradTile,
+    weights: Vec<Arc<cnn::WinogradWeights>>,
+    threads: usize,
+}
+
+impl Backend for WinogradBackend {
+    fn name(&self) -> &'static str {
+        match self.tile {
+            WinogradTile::F2x2 => "winograd2",
+            WinogradTile::F4x4 => "winograd4",
+        }
+    }
+
+    fn prepare(&self, model: Model) -> Box<PreparedModel> {
+        let weights = model.iter().map(|l| Arc::new(cnn::winograd_pack_weights(l, self.tile))).collect();
+        Box::new(WinogradModel { model: model, tile: self.tile, weights: weights, threads: self.threads })
+    }
+}
+
+impl PreparedModel for WinogradModel {
+    fn model(&self) -> &Model {
+        &self.model
+    }
+
+    fn filter(&self, img: Image, perf: &mut PerfStatus) -> Image {
+        cnn::filter_winograd(img, &self.model, &self.weights, self.threads, perf)
+    }
+
+    fn buffer_size(&self, width: usize, height: usize) -> usize {
+        cnn::filter_winograd_buffer_size(width, height, &self.model, self.tile, self.threads)
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel};
+use std::thread;
+
+use glob;
+use time;
+
+use error::{Error, Result};
+use image::{self, Image};
+use {PerfStatus, Upscaled, Upscaler};
+
+/// default for the output file name template
+pub const DEFAULT_TEMPLATE: &'static str = "{stem}.{ext}";
+
+/// An input image of a batch run.
+#[derive(Clone, Debug)]
+pub struct BatchItem {
+    pub input: PathBuf,
+    /// path below the directory (or glob prefix) the input was found in,
+    /// used to mirror the directory layout in the output directory
+    pub relative: PathBuf,
+}
+
+/// An input image and the path its result is written to.
+#[derive(Clone, Debug)]
+pub struct BatchJob {
+    pub item: BatchItem,
+    pub output: PathBuf,
+}
+
+/// Outcome of one BatchJob, `index` refers to the slice passed to run_pipeline.
+pub struct BatchResult {
+    pub index: usize,
+    pub result: Result<PerfStatus>,
+    /// noise level the image was filtered with, see Upscaled::noise_level
+    pub noise_level: Option<u32>,
+    pub decode_time: f64,
+    pub upscale_time: f64,
+    pub encode_time: f64,
+}
+
+/// Expands files, directories and glob patterns into the list of input images.
+/// Directories are scanned for supported image files, recursively if requested.
+pub fn collect_inputs<S: AsRef<str>>(args: &[S], recursive: bool) -> Result<Vec<BatchItem>> {
+    let mut items = Vec::new();
+    for arg in args.iter() {
+        let arg = arg.as_ref();
+        let path = Path::new(arg);
+        // an existing file is taken literally even if its name contains wildcards
+        if path.is_dir() {
+            try!(scan_dir(path, path, recursive, &mut items));
+        } else if path.is_file() {
+            items.push(BatchItem {
+                input: path.to_path_buf(),
+                relative: PathBuf::from(path.file_name().unwrap()),
+            });
+        } else if is_pattern(arg) {
+            try!(expand_pattern(arg, &mut items));
+        } else {
+            return Err(Error::IOError(io::Error::new(
+                io::ErrorKind::NotFound, format!("no such file or directory: {}", arg))));
+        }
+    }
+    Ok(items)
+}
+
+/// Builds the output path of `item` inside `out_dir`. The template may use
+/// {stem} (file name without extension), {ext} (extension) and {name} (file name).
+/// {ext} of an input format which cannot be written (TIFF) becomes png, which
+/// keeps 16-bit samples.
+pub fn output_path<P: AsRef<Path>>(out_dir: P, template: &str, item: &BatchItem) -> PathBuf {
+    let file_name = item.relative.file_name().and_then(|x| x.to_str()).unwrap_or("");
+    let stem = item.relative.file_stem().and_then(|x| x.to_str()).unwrap_or("");
+    let ext = match item.relative.extension().and_then(|x| x.to_str()) {
+        Some(ext) if image::check_path(&item.relative).is_ok() => ext,
+        Some(_) => "png",
+        None => "",
+    };
+    let name = template.replace("{stem}", stem)
+        .replace("{ext}", ext)
+        .replace("{name}", file_name);
+    let mut path = out_dir.as_ref().to_path_buf();
+    if let Some(parent) = item.relative.parent() {
+        path.push(parent);
+    }
+    path.push(name);
+    path
+}
+
+/// Pairs every item with its output path (see output_path). Fails if an output
+/// path has a format which cannot be written, or if two inputs would be written
+/// to the same file, e.g. `a/x.png` and `b/x.png` given as files.
+pub fn plan_jobs<P: AsRef<Path>>(items: Vec<BatchItem>, out_dir: P, template: &str) -> Result<Vec<BatchJob>> {
+    let mut seen: HashMap<PathBuf, usize> = HashMap::new();
+    let mut jobs: Vec<BatchJob> = Vec::with_capacity(items.len());
+    for item in items {
+        let output = output_path(&out_dir, template, &item);
+        try!(image::check_path(&output));
+        if let Some(&i) = seen.get(&output) {
+            return Err(Error::InvalidArgument(format!(
+                "{} and {} would both be written to {}", jobs[i].item.input.display(),
+                item.input.display(), output.display())));
+        }
+        seen.insert(output.clone(), jobs.len());
+        jobs.push(BatchJob { item: item, output: output });
+    }
+    Ok(jobs)
+}
+
+fn is_supported(path: &Path) -> bool {
+    image::check_input_path(path).is_ok()
+}
+
+fn is_pattern(arg: &str) -> bool {
+    arg.contains('*') || arg.contains('?') || arg.contains('[')
+}
+
+fn scan_dir(root: &Path, dir: &Path, recursive: bool, items: &mut Vec<BatchItem>) -> Result<()> {
+    let mut entries = Vec::new();
+    for entry in try!(fs::read_dir(dir)) {
+        entries.push(try!(entry).path());
+    }
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            if recursive {
+                try!(scan_dir(root, &path, recursive, items));
+            }
+        } else if is_supported(&path) {
+            let relative = path.strip_prefix(root).unwrap().to_path_buf();
+            items.push(BatchItem { input: path, relative: relative });
+        }
+    }
+    Ok(())
+}
+
+fn expand_pattern(pattern: &str, items: &mut Vec<BatchItem>) -> Result<()> {
+    // the leading components without wildcards act as the root directory
+    let mut root = PathBuf::new();
+    for c in Path::new(pattern).components() {
+        let s = match c {
+            Component::Normal(s) => s.to_str().unwrap_or(""),
+            _ => "",
+        };
+        if is_pattern(s) {
+            break;
+        }
+        root.push(c.as_os_str());
+    }
+
+    let paths = match glob::glob(pattern) {
+        Ok(p) => p,
+        Err(e) => return Err(Error::InvalidArgument(format!("invalid pattern \"{}\": {}", pattern, e))),
+    };
+    for entry in paths {
+        let path = match entry {
+            Ok(p) => p,
+            Err(e) => return Err(Error::IOError(io::Error::new(e.error().kind(), format!("{}", e)))),
+        };
+        if !path.is_file() || !is_supported(&path) {
+            continue;
+        }
+        let relative = match path.strip_prefix(&root) {
+            Ok(r) => r.to_path_buf(),
+            Err(_) => PathBuf::from(path.file_name().unwrap()),
+        };
+        items.push(BatchItem { input: path.clone(), relative: relative });
+    }
+    Ok(())
+}
+
+/// Processes the jobs in three stages: `io_threads` threads decode images and
+/// another `io_threads` encode the results, while the calling thread runs the
+/// CNN. The queues between the stages hold at most `io_threads` images each.
+/// `report` is called on the calling thread as jobs finish (in completion order).
+pub fn run_pipeline<F>(upscaler: &Upscaler, jobs: &[BatchJob], io_threads: usize, mut report: F)
+    where F: FnMut(BatchResult)
+{
+    let io_threads = ::std::cmp::max(1, io_threads);
+    let jobs = Arc::new(jobs.to_vec());
+
+    // decode stage
+    let next = Arc::new(AtomicUsize::new(0));
+    let (decoded_tx, decoded_rx) = sync_channel::<(usize, Result<Image>, f64)>(io_threads);
+    let mut workers = Vec::new();
+    for _ in 0..io_threads {
+        let (jobs, next, tx) = (jobs.clone(), next.clone(), decoded_tx.clone());
+        workers.push(thread::spawn(move || {
+            loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= jobs.len() {
+                    break;
+                }
+                let start = time::precise_time_s();
+                let img = image::check_path(&jobs[i].output)
+                    .and_then(|_| image::load(&jobs[i].item.input));
+                if tx.send((i, img, time::precise_time_s() - start)).is_err() {
+                    break;
+                }
+            }
+        }));
+    }
+    drop(decoded_tx);
+
+    // encode stage
+    let (encode_tx, encode_rx) = sync_channel::<(usize, Upscaled, f64, f64)>(io_threads);
+    let encode_rx = Arc::new(Mutex::new(encode_rx));
+    let (done_tx, done_rx) = channel::<BatchResult>();
+    for _ in 0..io_threads {
+        let (jobs, rx, tx) = (jobs.clone(), encode_rx.clone(), done_tx.clone());
+        workers.push(thread::spawn(move || {
+            loop {
+                let msg = rx.lock().unwrap().recv();
+                let (i, upscaled, decode_time, upscale_time) = match msg {
+                    Ok(m) => m,
+                    Err(_) => break,
+                };
+                let start = time::precise_time_s();
+                let Upscaled { image: img, perf, noise_level } = upscaled;
+                let result = save(&img, &jobs[i].output).map(|_| perf);
+                let _ = tx.send(BatchResult {
+                    index: i,
+                    result: result,
+                    noise_level: noise_level,
+                    decode_time: decode_time,
+                    upscale_time: upscale_time,
+                    encode_time: time::precise_time_s() - start,
+                });
+            }
+        }));
+    }
+    drop(done_tx);
+
+    // CNN stage
+    for (i, img, decode_time) in decoded_rx.iter() {
+        let start = time::precise_time_s();
+        let upscaled = img.and_then(|img| upscaler.upscale_file(img, &jobs[i].item.input));
+        let upscale_time = time::precise_time_s() - start;
+        match upscaled {
+            Ok(u) => encode_tx.send((i, u, decode_time, upscale_time)).unwrap(),
+            Err(e) => report(BatchResult {
+                index: i,
+                result: Err(e),
+                noise_level: None,
+                decode_time: decode_time,
+                upscale_time: upscale_time,
+                encode_time: 0.0,
+            }),
+        }
+        while let Ok(r) = done_rx.try_recv() {
+            report(r);
+        }
+    }
+    drop(encode_tx);
+    for r in done_rx.iter() {
+        report(r);
+    }
+    for w in workers {
+        w.join().unwrap();
+    }
+}
+
+fn save(img: &Image, path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        try!(fs::create_dir_all(dir));
+    }
+    image::save(img, path)
+}
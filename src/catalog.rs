@@ -0,0 +1,231 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use model::{self, LoadModelError, PackedModel};
+use super::{Method, NoiseLevel};
+
+/// Environment variable with additional model root directories (separated like PATH).
+pub const MODEL_PATH_ENV: &'static str = "WAIFU2X_MODEL_PATH";
+
+/// Style used when neither a model directory nor a style is given.
+pub const DEFAULT_STYLE: &'static str = "anime_style_art";
+
+/// A model file of a model directory.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ModelKind {
+    Scale2x,
+    Noise(u32),
+}
+
+impl ModelKind {
+    /// file name without the .json/.bin extension
+    pub fn file_stem(&self) -> String {
+        match *self {
+            ModelKind::Scale2x => "scale2.0x_model".to_string(),
+            ModelKind::Noise(level) => format!("noise{}_model", level),
+        }
+    }
+}
+
+/// Resolves the model files of a model directory and loads them on demand.
+pub struct ModelCatalog {
+    dir: PathBuf,
+}
+
+impl ModelCatalog {
+    pub fn new<P: AsRef<Path>>(dir: P) -> ModelCatalog {
+        ModelCatalog { dir: dir.as_ref().to_path_buf() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Models `method` runs on. NoiseLevel::Auto leaves out the noise model,
+    /// any of noise_levels() will do.
+    pub fn required(method: Method, noise_level: NoiseLevel) -> Vec<ModelKind> {
+        let mut kinds = Vec::new();
+        if method != Method::Scale {
+            if let NoiseLevel::Level(level) = noise_level {
+                kinds.push(ModelKind::Noise(level));
+            }
+        }
+        if method != Method::Noise {
+            kinds.push(ModelKind::Scale2x);
+        }
+        kinds
+    }
+
+    /// Path of the model file, the binary model is preferred over the JSON one.
+    pub fn path(&self, kind: ModelKind) -> Option<PathBuf> {
+        let stem = kind.file_stem();
+        for ext in ["bin", "json"].iter() {
+            let path = self.dir.join(format!("{}.{}", stem, ext));
+            if path.is_file() {
+                return Some(path);
+            }
+        }
+        None
+    }
+
+    pub fn has(&self, kind: ModelKind) -> bool {
+        self.path(kind).is_some()
+    }
+
+    /// Noise levels with a noise{N}_model file, sorted.
+    pub fn noise_levels(&self) -> Vec<u32> {
+        let mut levels: Vec<u32> = self.model_stems().iter()
+            .filter_map(|s| {
+                if s.starts_with("noise") && s.ends_with("_model") {
+                    s["noise".len()..s.len() - "_model".len()].parse().ok()
+                } else {
+                    None
+                }
+            })
+            .collect();
+        levels.sort();
+        levels.dedup();
+        levels
+    }
+
+    /// Scale factors with a scale{X}x_model file, e.g. "2.0", sorted.
+    pub fn scales(&self) -> Vec<String> {
+        let mut scales: Vec<String> = self.model_stems().iter()
+            .filter(|s| s.starts_with("scale") && s.ends_with("x_model"))
+            .map(|s| s["scale".len()..s.len() - "x_model".len()].to_string())
+            .collect();
+        scales.sort();
+        scales.dedup();
+        scales
+    }
+
+    /// Number of input planes of the models (1 for Y, 3 for RGB), None if no model can be read.
+    pub fn input_planes(&self) -> Option<u32> {
+        let mut kinds = vec![ModelKind::Scale2x];
+        kinds.extend(self.noise_levels().into_iter().map(ModelKind::Noise));
+        for kind in kinds {
+            if let Some(path) = self.path(kind) {
+                if let Ok(m) = model::load_model_unchecked(path) {
+                    return m.first().map(|l| l.nInputPlane);
+                }
+            }
+        }
+        None
+    }
+
+    // file stems of all .json/.bin files in the directory
+    fn model_stems(&self) -> Vec<String> {
+        let mut stems = Vec::new();
+        if let Ok(entries) = fs::read_dir(&self.dir) {
+            for entry in entries {
+                let path = match entry {
+                    Ok(e) => e.path(),
+                    Err(_) => continue,
+                };
+                let ext = path.extension().and_then(|x| x.to_str()).unwrap_or("").to_string();
+                if ext != "json" && ext != "bin" {
+                    continue;
+                }
+                if let Some(stem) = path.file_stem().and_then(|x| x.to_str()) {
+                    stems.push(stem.to_string());
+                }
+            }
+        }
+        stems
+    }
+
+    pub fn load(&self, kind: ModelKind) -> Result<PackedModel, LoadModelError> {
+        match self.path(kind) {
+            Some(path) => model::load_packed_model(path),
+            None => Err(LoadModelError::NotFound {
+                files: vec![format!("{}.json", kind.file_stem()),
+                            format!("{}.bin", kind.file_stem())],
+                dir: self.dir.clone(),
+            }),
+        }
+    }
+}
+
+/// Directories searched for model styles, in order: $WAIFU2X_MODEL_PATH,
+/// $XDG_DATA_HOME/waifu2x-hsa/models (~/.local/share by default),
+/// $XDG_DATA_DIRS/waifu2x-hsa/models (/usr/local/share:/usr/share by default)
+/// and the `models` directory next to the executable.
+pub fn search_path() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(paths) = env::var_os(MODEL_PATH_ENV) {
+        dirs.extend(env::split_paths(&paths).filter(|p| !p.as_os_str().is_empty()));
+    }
+    let data_home = match env::var_os("XDG_DATA_HOME") {
+        Some(ref p) if !p.is_empty() => Some(PathBuf::from(p)),
+        _ => env::home_dir().map(|h| h.join(".local").join("share")),
+    };
+    if let Some(p) = data_home {
+        dirs.push(p.join("waifu2x-hsa").join("models"));
+    }
+    let data_dirs = match env::var_os("XDG_DATA_DIRS") {
+        Some(ref p) if !p.is_empty() => p.clone(),
+        _ => "/usr/local/share:/usr/share".into(),
+    };
+    for p in env::split_paths(&data_dirs) {
+        dirs.push(p.join("waifu2x-hsa").join("models"));
+    }
+    if let Some(dir) = env::current_exe().ok().and_then(|p| p.parent().map(|p| p.to_path_buf())) {
+        dirs.push(dir.join("models"));
+    }
+    dirs
+}
+
+/// Model directories (styles) below the given roots as (style name, catalog).
+pub fn styles(roots: &[PathBuf]) -> Vec<(String, ModelCatalog)> {
+    let mut result = Vec::new();
+    for root in roots.iter() {
+        let mut dirs: Vec<PathBuf> = match fs::read_dir(root) {
+            Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.is_dir()).collect(),
+            Err(_) => continue,
+        };
+        dirs.sort();
+        for dir in dirs {
+            let catalog = ModelCatalog::new(&dir);
+            if catalog.model_stems().is_empty() {
+                continue;
+            }
+            let name = dir.file_name().and_then(|x| x.to_str()).unwrap_or("").to_string();
+            result.push((name, catalog));
+        }
+    }
+    result
+}
+
+/// Finds the model directory of `style` below the roots which has every model
+/// `method` needs. `<style>_rgb` is preferred over the Y model directory `<style>`;
+/// "anime" is short for "anime_style_art".
+pub fn find_style(roots: &[PathBuf], style: &str, method: Method, noise_level: NoiseLevel)
+                  -> Result<ModelCatalog, LoadModelError> {
+    let style = if style == "anime" { DEFAULT_STYLE } else { style };
+    let mut names = Vec::new();
+    if !style.ends_with("_rgb") {
+        names.push(format!("{}_rgb", style));
+    }
+    names.push(style.to_string());
+
+    let required = ModelCatalog::required(method, noise_level);
+    let any_noise = noise_level == NoiseLevel::Auto && method != Method::Scale;
+    for root in roots.iter() {
+        for name in names.iter() {
+            let catalog = ModelCatalog::new(root.join(name));
+            if required.iter().all(|k| catalog.has(*k)) && (!any_noise || !catalog.noise_levels().is_empty()) {
+                return Ok(catalog);
+            }
+        }
+    }
+    let mut files: Vec<String> = required.iter().map(|k| format!("{}.json", k.file_stem())).collect();
+    if any_noise {
+        files.insert(0, "noise*_model.json".to_string());
+    }
+    Err(LoadModelError::StyleNotFound {
+        style: style.to_string(),
+        files: files,
+        searched: roots.to_vec(),
+    })
+}
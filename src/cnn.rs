@@ -1,9 +1,75 @@
 use std;
+use std::panic;
+use std::sync::{Arc, Barrier, RwLock};
+use std::thread;
+use time;
 use image::Image;
-use model::{Model, Layer};
+use model::{self, Model, Layer, LayerWeights};
 use super::PerfStatus;
 
+/// Floating point operations of a layer producing a `width` x `height` output.
+/// Saturates instead of overflowing for the plane counts of broken models.
+pub fn layer_flops(layer: &Layer, width: usize, height: usize) -> u64 {
+    let pixels = (width as u64).saturating_mul(height as u64);
+    let macs = [layer.nOutputPlane, layer.kW, layer.kH].iter()
+        .fold(layer.nInputPlane as u64, |acc, &v| acc.saturating_mul(v as u64));
+    macs.saturating_mul(2).saturating_add(layer.nOutputPlane as u64).saturating_mul(pixels)
+}
+
+/// Floating point operations needed to run the model on a `width` x `height` (padded) input.
+pub fn flops(model: &Model, width: usize, height: usize) -> u64 {
+    let (mut width, mut height) = (width, height);
+    let mut flo = 0;
+    for layer in model.iter() {
+        width -= layer.kW as usize - 1;
+        height -= layer.kH as usize - 1;
+        flo += layer_flops(layer, width, height);
+    }
+    flo
+}
+
+// The kernels index without bounds checks, so the public filter functions reject
+// (by panicking, like slice indexing) models and images which do not fit together.
+fn check_input(img: &Image, model: &Model) {
+    if let Err(e) = model::validate_layers(model) {
+        panic!("invalid model: {}", e);
+    }
+    let (pad_x, pad_y) = model::padding(model);
+    assert!(img.width >= pad_x * 2 && img.height >= pad_y * 2,
+            "{}x{} image is smaller than the {}x{} the model crops off",
+            img.width, img.height, pad_x * 2, pad_y * 2);
+    let num_in = model[0].nInputPlane as usize;
+    assert!(img.data.len() >= num_in && img.strides.len() >= num_in,
+            "image has {} planes, the model takes {}", img.data.len(), num_in);
+    for k in 0..num_in {
+        assert!(img.strides[k] >= img.width && img.data[k].len() >= img.strides[k] * img.height,
+                "plane {} is smaller than {}x{}", k, img.width, img.height);
+    }
+}
+
+// weights packed by model::pack_weights for every layer of `model`
+fn check_packed_weights(model: &Model, weights: &[LayerWeights]) {
+    assert!(weights.len() == model.len(), "{} packed layers for a {} layer model", weights.len(), model.len());
+    for (i, (layer, w)) in model.iter().zip(weights.iter()).enumerate() {
+        check_direct_weights(i, layer, w);
+    }
+}
+
+fn check_direct_weights(i: usize, layer: &Layer, w: &[Vec<f32>]) {
+    let size = (layer.nOutputPlane * layer.kH * layer.kW) as usize;
+    assert!(w.len() == layer.nInputPlane as usize && w.iter().all(|p| p.len() == size),
+            "layer {}: packed weights do not match its shape", i);
+}
+
 pub fn filter_cpu1(in_img: Image, model: &Model, perf: &mut PerfStatus) -> Image {
+    check_input(&in_img, model);
+    if let Err(e) = model::validate_model(model) {
+        panic!("invalid model: {}", e);
+    }
+    // every plane is read with the stride of the first one
+    let num_in = model[0].nInputPlane as usize;
+    assert!(in_img.strides[..num_in].iter().all(|&s| s == in_img.strides[0]),
+            "filter_cpu1 needs the same stride for every plane");
 
     let mut in_maps = in_img.data;
     let mut out_maps: Vec<Vec<f32>> = Vec::new();
@@ -12,19 +78,24 @@ pub fn filter_cpu1(in_img: Image, model: &Model, perf: &mut PerfStatus) -> Image
     let mut height = in_img.height;
 
     for layer in model.iter() {
-        let new_width = width - 2;
-        let new_height = height - 2;
+        let (kw, kh) = (layer.kW as usize, layer.kH as usize);
+        let new_width = width - (kw - 1);
+        let new_height = height - (kh - 1);
 
         unsafe {
             for i in 0..layer.nOutputPlane {
                 let bias = &layer.bias[i as usize];
                 let weights = &layer.weight[i as usize];
-                let mut out: Vec<f32> = Vec::with_capacity(new_width * new_height);
-                out.set_len(new_width * new_height);
+                let mut out: Vec<f32> = vec![0.0; new_width * new_height];
 
                 for j in 0..layer.nInputPlane as usize {
                     let in_map = in_maps.get_unchecked(j);
                     let w = weights.get_unchecked(j);
+                    if kw != 3 || kh != 3 {
+                        filter_cpu1_convolve(in_map, w, kw, kh, width_stride,
+                                             new_width, new_height, &mut out);
+                        continue;
+                    }
                     let w00 = *w.get_unchecked(0).get_unchecked(0);
                     let w01 = *w.get_unchecked(0).get_unchecked(1);
                     let w02 = *w.get_unchecked(0).get_unchecked(2);
@@ -68,9 +139,7 @@ pub fn filter_cpu1(in_img: Image, model: &Model, perf: &mut PerfStatus) -> Image
         width = new_width;
         height = new_height;
         width_stride = width;
-        perf.cnn_flo +=
-            ((layer.nInputPlane * layer.nOutputPlane * layer.kW * layer.kH * 2) as u64 * (width * height) as u64) +
-            (layer.nOutputPlane as u64 * width as u64 * height as u64);
+        perf.cnn_flo += layer_flops(layer, width, height);
 
         in_maps = out_maps;
         out_maps = Vec::new();
@@ -85,65 +154,166 @@ pub fn filter_cpu1(in_img: Image, model: &Model, perf: &mut PerfStatus) -> Image
         width: width,
         height: height,
         color_space: in_img.color_space.clone(),
+        has_alpha: false,
+        sample_format: in_img.sample_format,
         data: out_maps,
         strides: out_strides,
     }
 }
 
-pub fn filter_cpu2(in_img: Image, model: &Model, perf: &mut PerfStatus) -> Image {
-    let stride = {
-        let mut max_stride: usize = 1;
-        for i in 0..model.len() as usize {
-            let layer = &model[i];
-            max_stride = std::cmp::max(max_stride, std::cmp::max(
-                (in_img.width - i * 2) * layer.nInputPlane as usize,
-                (in_img.width - (i + 1) * 2) * layer.nOutputPlane as usize));
-        }
-        max_stride
-    };
-    let mut buf = Vec::<f32>::with_capacity(stride * in_img.height);
+fn filter_cpu1_convolve(in_map: &Vec<f32>, w: &Vec<Vec<f32>>, kw: usize, kh: usize,
+                        width_stride: usize, width: usize, height: usize, out: &mut Vec<f32>) {
     unsafe {
-        let capacity = buf.capacity();
-        buf.set_len(capacity);
-    }
-
-    {
-        let cnt = model[0].nInputPlane as usize;
-        for y in 0..in_img.height {
-            let off = stride * y;
-            for i in 0..cnt {
-                let src_off = in_img.strides[i] * y;
-                for x in 0..in_img.width {
-                    buf[off + x * cnt + i] = in_img.data[i][src_off + x];
+        for y in 0..height {
+            let off_dst = y * width;
+            for x in 0..width {
+                let mut sum = 0.0;
+                for ky in 0..kh {
+                    let wk = w.get_unchecked(ky);
+                    let off_src = (y + ky) * width_stride + x;
+                    for kx in 0..kw {
+                        sum += *in_map.get_unchecked(off_src + kx) * *wk.get_unchecked(kx);
+                    }
                 }
+                *out.get_unchecked_mut(off_dst + x) += sum;
             }
         }
     }
+}
+
+pub fn filter_cpu2(in_img: Image, model: &Model, perf: &mut PerfStatus) -> Image {
+    let weights: Vec<LayerWeights> = model.iter().map(|l| Arc::new(model::pack_weights(l))).collect();
+    filter_cpu2_packed(in_img, model, &weights, perf)
+}
 
-    let mut temp: [f32; 128] = [0.0; 128];
+/// filter_cpu2 with the weights already packed by model::pack_weights.
+pub fn filter_cpu2_packed(in_img: Image, model: &Model, weights: &[LayerWeights],
+                          perf: &mut PerfStatus) -> Image {
+    check_input(&in_img, model);
+    check_packed_weights(model, weights);
+    let stride = filter_cpu2_stride(in_img.width, model);
+    let mut buf = filter_cpu2_pack(&in_img, model[0].nInputPlane as usize, stride);
+
+    let mut temp: Vec<f32> = vec![0.0; filter_cpu2_max_planes(model)];
     let mut out_line: Vec<f32> = Vec::with_capacity(stride);
     unsafe { out_line.set_len(stride); }
     let (mut width, mut height) = (in_img.width, in_img.height);
 
+    for (layer, weights) in model.iter().zip(weights.iter()) {
+        let (kw, kh) = (layer.kW as usize, layer.kH as usize);
+        width -= kw - 1;
+        height -= kh - 1;
+        perf.cnn_flo += layer_flops(layer, width, height);
+
+        filter_cpu2_layer_any(weights, &layer.bias, layer.nInputPlane as usize, layer.nOutputPlane as usize,
+                              kw, kh, width, height, stride, &mut temp, &mut buf, &mut out_line);
+    }
+
+    filter_cpu2_unpack(&buf, model[model.len() - 1].nOutputPlane as usize,
+                       width, height, stride, &in_img)
+}
+
+/// Multithreaded variant of filter_cpu2.
+///
+/// The worker threads are started once per call. Each layer's output rows are split
+/// into horizontal bands (one per thread); a band is copied together with its (kH - 1)-row
+/// halo and processed by the same kernels as filter_cpu2, so the result is bit-identical
+/// to the single-threaded path.
+pub fn filter_cpu2_mt(in_img: Image, model: &Model, threads: usize, perf: &mut PerfStatus) -> Image {
+    let weights: Vec<LayerWeights> = model.iter().map(|l| Arc::new(model::pack_weights(l))).collect();
+    filter_cpu2_mt_packed(in_img, model, &weights, threads, perf)
+}
+
+/// filter_cpu2_mt with the weights already packed by model::pack_weights.
+pub fn filter_cpu2_mt_packed(in_img: Image, model: &Model, weights: &[LayerWeights], threads: usize,
+                             perf: &mut PerfStatus) -> Image {
+    check_input(&in_img, model);
+    check_packed_weights(model, weights);
+    let threads = std::cmp::max(1, threads);
+    let stride = filter_cpu2_stride(in_img.width, model);
+    let mut buf = filter_cpu2_pack(&in_img, model[0].nInputPlane as usize, stride);
+    let (mut width, mut height) = (in_img.width, in_img.height);
+
+    let mut layers = Vec::with_capacity(model.len());
+    for (layer, weights) in model.iter().zip(weights.iter()) {
+        let (kw, kh) = (layer.kW as usize, layer.kH as usize);
+        width -= kw - 1;
+        height -= kh - 1;
+        perf.cnn_flo += layer_flops(layer, width, height);
+
+        let num_in = layer.nInputPlane as usize;
+        let num_out = layer.nOutputPlane as usize;
+        let (weights, bias) = (weights.clone(), layer.bias.clone());
+        layers.push(BandLayer::new(height, kh, 1, move |band, band_height| {
+            let mut temp: Vec<f32> = vec![0.0; num_out];
+            let mut out_line: Vec<f32> = Vec::with_capacity(stride);
+            unsafe { out_line.set_len(stride); }
+            filter_cpu2_layer_any(&weights, &bias, num_in, num_out, kw, kh, width, band_height,
+                                  stride, &mut temp, band, &mut out_line);
+        }));
+    }
+    buf = filter_bands(buf, stride, threads, layers, perf);
+
+    filter_cpu2_unpack(&buf, model[model.len() - 1].nOutputPlane as usize,
+                       width, height, stride, &in_img)
+}
+
+/// Number of bytes filter_cpu2 (or filter_cpu2_mt with `threads` > 1) allocates
+/// for its working buffers when processing a `width` x `height` padded input.
+/// With threads the bands (the rows plus a (kH - 1)-row halo each) coexist with the layer buffer.
+pub fn filter_cpu2_buffer_size(width: usize, height: usize, model: &Model, threads: usize) -> usize {
+    let stride = filter_cpu2_stride(width, model);
+    let size = stride * height * std::mem::size_of::<f32>();
+    if threads == 1 {
+        return size;
+    }
+    let halo = model.iter().map(|l| l.kH as usize - 1).max().unwrap_or(0);
+    size * 2 + halo * threads * stride * std::mem::size_of::<f32>()
+}
+
+fn filter_cpu2_max_planes(model: &Model) -> usize {
+    let mut max_planes: usize = 1;
+    for layer in model.iter() {
+        max_planes = std::cmp::max(max_planes, layer.nOutputPlane as usize);
+    }
+    max_planes
+}
+
+fn filter_cpu2_stride(width: usize, model: &Model) -> usize {
+    let mut max_stride: usize = 1;
+    let mut width = width;
     for layer in model.iter() {
-        width -= 2;
-        height -= 2;
-        perf.cnn_flo +=
-            ((layer.nInputPlane * layer.nOutputPlane * layer.kW * layer.kH * 2) as u64 * (width * height) as u64) +
-            (layer.nOutputPlane as u64 * width as u64 * height as u64);
-
-        if layer.nOutputPlane < 32 {
-            filter_cpu2_layer(layer, layer.nInputPlane as usize, layer.nOutputPlane as usize,
-                              width, height, stride, &mut temp, &mut buf, &mut out_line);
-        } else {
-            filter_cpu2_layer_32(layer, layer.nInputPlane as usize, layer.nOutputPlane as usize,
-                                 width, height, stride, &mut temp, &mut buf, &mut out_line);
+        let new_width = width - (layer.kW as usize - 1);
+        max_stride = std::cmp::max(max_stride, std::cmp::max(
+            width * layer.nInputPlane as usize,
+            new_width * layer.nOutputPlane as usize));
+        width = new_width;
+    }
+    max_stride
+}
+
+fn filter_cpu2_pack(in_img: &Image, cnt: usize, stride: usize) -> Vec<f32> {
+    let mut buf = Vec::<f32>::with_capacity(stride * in_img.height);
+    unsafe {
+        let capacity = buf.capacity();
+        buf.set_len(capacity);
+    }
+    for y in 0..in_img.height {
+        let off = stride * y;
+        for i in 0..cnt {
+            let src_off = in_img.strides[i] * y;
+            for x in 0..in_img.width {
+                buf[off + x * cnt + i] = in_img.data[i][src_off + x];
+            }
         }
     }
+    buf
+}
 
+fn filter_cpu2_unpack(buf: &Vec<f32>, num_out: usize, width: usize, height: usize,
+                      stride: usize, in_img: &Image) -> Image {
     let out_maps = {
         let mut out_maps = Vec::new();
-        let num_out = model[model.len() - 1].nOutputPlane as usize;
         for i in 0..num_out {
             let mut v = Vec::with_capacity(width * height);
             unsafe { v.set_len(width * height); }
@@ -169,15 +339,34 @@ pub fn filter_cpu2(in_img: Image, model: &Model, perf: &mut PerfStatus) -> Image
         width: width,
         height: height,
         color_space: in_img.color_space.clone(),
+        has_alpha: false,
+        sample_format: in_img.sample_format,
         data: out_maps,
         strides: out_strides,
     }
 }
 
-fn filter_cpu2_layer(layer: &Layer, num_in: usize, num_out: usize,
+// Picks the fastest kernel applicable to the layer. Every kernel handles any
+// number of input/output planes; filter_cpu2_layer_generic accepts any kernel size.
+#[inline(always)]
+fn filter_cpu2_layer_any(weights: &Vec<Vec<f32>>, bias: &[f32], num_in: usize, num_out: usize,
+                         kw: usize, kh: usize, width: usize, height: usize, stride: usize,
+                         temp: &mut [f32], buf: &mut Vec<f32>, out_line: &mut Vec<f32>) {
+    if kw == 1 && kh == 1 {
+        filter_cpu2_layer_1x1(weights, bias, num_in, num_out, width, height, stride, temp, buf, out_line);
+    } else if kw != 3 || kh != 3 {
+        filter_cpu2_layer_generic(weights, bias, num_in, num_out, kw, kh,
+                                  width, height, stride, temp, buf, out_line);
+    } else if num_out < 32 {
+        filter_cpu2_layer(weights, bias, num_in, num_out, width, height, stride, temp, buf, out_line);
+    } else {
+        filter_cpu2_layer_32(weights, bias, num_in, num_out, width, height, stride, temp, buf, out_line);
+    }
+}
+
+fn filter_cpu2_layer(weights: &Vec<Vec<f32>>, bias: &[f32], num_in: usize, num_out: usize,
                      width: usize, height: usize, stride: usize,
-                     temp: &mut [f32; 128], buf: &mut Vec<f32>, out_line: &mut Vec<f32>) {
-    let weights = filter_cpu2_get_weights(&layer);
+                     temp: &mut [f32], buf: &mut Vec<f32>, out_line: &mut Vec<f32>) {
     let mut input: [f32; 9] = [0.0; 9];
     unsafe {
         for y in 0..height {
@@ -206,17 +395,17 @@ fn filter_cpu2_layer(layer: &Layer, num_in: usize, num_out: usize,
                         *temp.get_unchecked_mut(j) += convolve3x3(&input, &w, j * 9);
                     }
                 }
-                filter_cpu2_layer_bias_relu(&layer, num_out, x, temp, out_line);
+                filter_cpu2_layer_bias_relu(bias, num_out, x, temp, out_line);
             }
             filter_cpu2_layer_writeback_line(num_out, width, y, stride, buf, out_line);
         }
     }
 }
 
-fn filter_cpu2_layer_32(layer: &Layer, num_in: usize, num_out: usize,
+fn filter_cpu2_layer_32(weights: &Vec<Vec<f32>>, bias: &[f32], num_in: usize, num_out: usize,
                         width: usize, height: usize, stride: usize,
-                        temp: &mut [f32; 128], buf: &mut Vec<f32>, out_line: &mut Vec<f32>) {
-    let weights = filter_cpu2_get_weights(&layer);
+                        temp: &mut [f32], buf: &mut Vec<f32>, out_line: &mut Vec<f32>) {
+    let num_out8 = num_out - num_out % 8;
     let mut input: [f32; 9] = [0.0; 9];
     unsafe {
         for y in 0..height {
@@ -241,7 +430,7 @@ fn filter_cpu2_layer_32(layer: &Layer, num_in: usize, num_out: usize,
                     *input.get_unchecked_mut(6) = *buf.get_unchecked(in_off20 + i);
                     *input.get_unchecked_mut(7) = *buf.get_unchecked(in_off21 + i);
                     *input.get_unchecked_mut(8) = *buf.get_unchecked(in_off22 + i);
-                    for j in (0..num_out-7).step_by(8) {
+                    for j in (0..num_out8).step_by(8) {
                         let wi = j * 9;
                         *temp.get_unchecked_mut(j + 0) += convolve3x3(&input, &w, wi);
                         *temp.get_unchecked_mut(j + 1) += convolve3x3(&input, &w, wi + 9);
@@ -252,14 +441,77 @@ fn filter_cpu2_layer_32(layer: &Layer, num_in: usize, num_out: usize,
                         *temp.get_unchecked_mut(j + 6) += convolve3x3(&input, &w, wi + 54);
                         *temp.get_unchecked_mut(j + 7) += convolve3x3(&input, &w, wi + 63);
                     }
+                    for j in num_out8..num_out {
+                        *temp.get_unchecked_mut(j) += convolve3x3(&input, &w, j * 9);
+                    }
+                }
+                filter_cpu2_layer_bias_relu(bias, num_out, x, temp, out_line);
+            }
+            filter_cpu2_layer_writeback_line(num_out, width, y, stride, buf, out_line);
+        }
+    }
+}
+
+fn filter_cpu2_layer_1x1(weights: &Vec<Vec<f32>>, bias: &[f32], num_in: usize, num_out: usize,
+                         width: usize, height: usize, stride: usize,
+                         temp: &mut [f32], buf: &mut Vec<f32>, out_line: &mut Vec<f32>) {
+    unsafe {
+        for y in 0..height {
+            for x in 0..width {
+                let in_off = y * stride + x * num_in;
+                for i in 0..num_in {
+                    let w = weights.get_unchecked(i);
+                    let input = *buf.get_unchecked(in_off + i);
+                    for j in 0..num_out {
+                        *temp.get_unchecked_mut(j) += input * *w.get_unchecked(j);
+                    }
                 }
-                filter_cpu2_layer_bias_relu(&layer, num_out, x, temp, out_line);
+                filter_cpu2_layer_bias_relu(bias, num_out, x, temp, out_line);
+            }
+            filter_cpu2_layer_writeback_line(num_out, width, y, stride, buf, out_line);
+        }
+    }
+}
+
+fn filter_cpu2_layer_generic(weights: &Vec<Vec<f32>>, bias: &[f32], num_in: usize, num_out: usize,
+                             kw: usize, kh: usize, width: usize, height: usize, stride: usize,
+                             temp: &mut [f32], buf: &mut Vec<f32>, out_line: &mut Vec<f32>) {
+    let ksize = kw * kh;
+    let mut input: Vec<f32> = vec![0.0; ksize];
+    unsafe {
+        for y in 0..height {
+            for x in 0..width {
+                let in_off = y * stride + x * num_in;
+                for i in 0..num_in {
+                    let w = weights.get_unchecked(i);
+                    for ky in 0..kh {
+                        for kx in 0..kw {
+                            *input.get_unchecked_mut(ky * kw + kx) =
+                                *buf.get_unchecked(in_off + ky * stride + kx * num_in + i);
+                        }
+                    }
+                    for j in 0..num_out {
+                        *temp.get_unchecked_mut(j) += convolve(&input, &w, j * ksize);
+                    }
+                }
+                filter_cpu2_layer_bias_relu(bias, num_out, x, temp, out_line);
             }
             filter_cpu2_layer_writeback_line(num_out, width, y, stride, buf, out_line);
         }
     }
 }
 
+#[inline(always)]
+fn convolve(input: &Vec<f32>, w: &Vec<f32>, off: usize) -> f32 {
+    let mut sum = 0.0;
+    unsafe {
+        for k in 0..input.len() {
+            sum += *input.get_unchecked(k) * *w.get_unchecked(off + k);
+        }
+    }
+    sum
+}
+
 #[inline(always)]
 fn convolve3x3(input: &[f32; 9], w: &Vec<f32>, off: usize) -> f32 {
     unsafe {
@@ -275,28 +527,12 @@ fn convolve3x3(input: &[f32; 9], w: &Vec<f32>, off: usize) -> f32 {
     }
 }
 
-fn filter_cpu2_get_weights(layer: &Layer) -> Vec<Vec<f32>> {
-    let mut w = Vec::with_capacity(layer.nInputPlane as usize);
-    for i in 0..layer.nInputPlane as usize {
-        let mut v = Vec::with_capacity(9 * layer.nOutputPlane as usize);
-        for j in 0..layer.nOutputPlane as usize {
-            for x in 0..3 {
-                for y in 0..3 {
-                    v.push(layer.weight[j][i][x][y]);
-                }
-            }
-        }
-        w.push(v);
-    }
-    w
-}
-
 #[inline(always)]
-fn filter_cpu2_layer_bias_relu(layer: &Layer, num_out: usize, x: usize,
-                               temp: &mut [f32; 128], out_line: &mut Vec<f32>) {
+fn filter_cpu2_layer_bias_relu(bias: &[f32], num_out: usize, x: usize,
+                               temp: &mut [f32], out_line: &mut Vec<f32>) {
     unsafe {
         for i in 0..num_out {
-            let mut v = *temp.get_unchecked(i) + *layer.bias.get_unchecked(i);
+            let mut v = *temp.get_unchecked(i) + *bias.get_unchecked(i);
             if v < 0.0 {
                 v *= 0.1;
             }
@@ -316,3 +552,621 @@ fn filter_cpu2_layer_writeback_line(num_out: usize, width: usize, y: usize, stri
         }
     }
 }
+
+// Register tile of gemm_micro_kernel: GEMM_MR pixels x GEMM_NR output planes.
+const GEMM_MR: usize = 4;
+const GEMM_NR: usize = 8;
+// Depth of a K block, sized so an A and a B panel of the block stay in L1.
+const GEMM_KC: usize = 256;
+// Output pixels lowered per im2col block; bands of rows wider than this are lowered
+// in several blocks so the block stays in L2 while it is multiplied with every N panel.
+const GEMM_BAND_PIXELS: usize = 256;
+
+/// Weights of a layer packed for filter_gemm: the (kH * kW * nInputPlane) x nOutputPlane
+/// matrix stored as panels of GEMM_NR output planes (zero padded), each panel row-major.
+pub struct GemmWeights {
+    k: usize,
+    panels: usize,
+    data: Vec<f32>,
+}
+
+pub fn gemm_pack_weights(layer: &Layer) -> GemmWeights {
+    let (kw, num_in) = (layer.kW as usize, layer.nInputPlane as usize);
+    let k = (layer.kH * layer.kW * layer.nInputPlane) as usize;
+    // row (ky * kW + kx) * nInputPlane + i, column o
+    gemm_pack_matrix(k, layer.nOutputPlane as usize, |row, o| {
+        let (i, kxy) = (row % num_in, row / num_in);
+        layer.weight[o][i][kxy / kw][kxy % kw]
+    })
+}
+
+// Packs the k x n matrix `value(row, column)` into GEMM_NR-column panels.
+fn gemm_pack_matrix<F: Fn(usize, usize) -> f32>(k: usize, n: usize, value: F) -> GemmWeights {
+    let panels = (n + GEMM_NR - 1) / GEMM_NR;
+    let mut data = vec![0.0; panels * k * GEMM_NR];
+    for col in 0..n {
+        let (p, r) = (col / GEMM_NR, col % GEMM_NR);
+        for row in 0..k {
+            data[(p * k + row) * GEMM_NR + r] = value(row, col);
+        }
+    }
+    GemmWeights { k: k, panels: panels, data: data }
+}
+
+impl GemmWeights {
+    // packed from a k x n matrix
+    fn fits(&self, k: usize, n: usize) -> bool {
+        self.k == k && self.panels == (n + GEMM_NR - 1) / GEMM_NR && self.data.len() == self.panels * k * GEMM_NR
+    }
+}
+
+/// Runs the model by lowering every layer to a matrix multiply: bands of output
+/// rows are unrolled with im2col into a (pixels x kH*kW*nInputPlane) matrix and
+/// multiplied with the packed weights by a cache-blocked, register-tiled kernel.
+/// Works for any kernel size; with `threads` > 1 the rows are split like filter_cpu2_mt.
+pub fn filter_gemm(in_img: Image, model: &Model, weights: &[Arc<GemmWeights>], threads: usize,
+                   perf: &mut PerfStatus) -> Image {
+    check_input(&in_img, model);
+    assert!(weights.len() == model.len(), "{} packed layers for a {} layer model", weights.len(), model.len());
+    for (i, (layer, w)) in model.iter().zip(weights.iter()).enumerate() {
+        assert!(w.fits((layer.kH * layer.kW * layer.nInputPlane) as usize, layer.nOutputPlane as usize),
+                "layer {}: gemm weights do not match its shape", i);
+    }
+    let threads = std::cmp::max(1, threads);
+    let stride = filter_cpu2_stride(in_img.width, model);
+    let mut buf = filter_cpu2_pack(&in_img, model[0].nInputPlane as usize, stride);
+    let (mut width, mut height) = (in_img.width, in_img.height);
+
+    let mut layers = Vec::new();
+    for (layer, weights) in model.iter().zip(weights.iter()) {
+        let (kw, kh) = (layer.kW as usize, layer.kH as usize);
+        width -= kw - 1;
+        height -= kh - 1;
+        perf.cnn_flo += layer_flops(layer, width, height);
+
+        let num_in = layer.nInputPlane as usize;
+        let num_out = layer.nOutputPlane as usize;
+        if threads == 1 {
+            filter_gemm_layer(weights, &layer.bias, num_in, num_out, kw, kh, width, height, stride, &mut buf);
+            continue;
+        }
+        let (weights, bias) = (weights.clone(), layer.bias.clone());
+        layers.push(BandLayer::new(height, kh, 1, move |band, band_height| {
+            filter_gemm_layer(&weights, &bias, num_in, num_out, kw, kh, width, band_height, stride, band);
+        }));
+    }
+    if threads > 1 {
+        buf = filter_bands(buf, stride, threads, layers, perf);
+    }
+
+    filter_cpu2_unpack(&buf, model[model.len() - 1].nOutputPlane as usize,
+                       width, height, stride, &in_img)
+}
+
+/// Number of bytes filter_gemm allocates when processing a `width` x `height` padded input.
+pub fn filter_gemm_buffer_size(width: usize, height: usize, model: &Model, threads: usize) -> usize {
+    let mut scratch = 0;
+    for layer in model.iter() {
+        let k = (layer.kW * layer.kH * layer.nInputPlane) as usize;
+        let n = (layer.nOutputPlane as usize + GEMM_NR - 1) / GEMM_NR * GEMM_NR;
+        let m = gemm_band_rows(width, height) * width + GEMM_MR;
+        let m_a = std::cmp::min(m, GEMM_BAND_PIXELS + GEMM_MR);
+        scratch = std::cmp::max(scratch, (m_a * k + m * n) * std::mem::size_of::<f32>());
+    }
+    filter_cpu2_buffer_size(width, height, model, threads) + scratch * threads
+}
+
+fn gemm_band_rows(width: usize, height: usize) -> usize {
+    std::cmp::max(1, std::cmp::min(height, GEMM_BAND_PIXELS / std::cmp::max(1, width)))
+}
+
+// Same in-place contract as filter_cpu2_layer_any: output row y replaces input row y.
+fn filter_gemm_layer(weights: &GemmWeights, bias: &[f32], num_in: usize, num_out: usize,
+                     kw: usize, kh: usize, width: usize, height: usize, stride: usize,
+                     buf: &mut Vec<f32>) {
+    let k = weights.k;
+    let n = weights.panels * GEMM_NR;
+    let band_rows = gemm_band_rows(width, height);
+    let max_a = (std::cmp::min(band_rows * width, GEMM_BAND_PIXELS) + GEMM_MR - 1) / GEMM_MR * GEMM_MR;
+    let mut a: Vec<f32> = vec![0.0; max_a * k];
+    // the padding of the last block spills past the band
+    let mut c: Vec<f32> = vec![0.0; (band_rows * width + GEMM_MR) * n];
+
+    let mut y = 0;
+    while y < height {
+        let rows = std::cmp::min(band_rows, height - y);
+        let pixels = rows * width;
+        let mut first = 0;
+        while first < pixels {
+            let m = std::cmp::min(GEMM_BAND_PIXELS, pixels - first);
+            gemm_im2col(buf, num_in, kw, kh, width, stride, y, first, m, k, &mut a);
+            gemm_multiply(&a, (m + GEMM_MR - 1) / GEMM_MR, weights, &mut c[first * n..]);
+            first += m;
+        }
+
+        // the band's input rows were lowered into `a`, so they can be overwritten
+        unsafe {
+            for r in 0..rows {
+                let out_off = (y + r) * stride;
+                for x in 0..width {
+                    let c_off = (r * width + x) * n;
+                    for o in 0..num_out {
+                        let mut v = *c.get_unchecked(c_off + o) + *bias.get_unchecked(o);
+                        if v < 0.0 {
+                            v *= 0.1;
+                        }
+                        *buf.get_unchecked_mut(out_off + x * num_out + o) = v;
+                    }
+                }
+            }
+        }
+        y += rows;
+    }
+}
+
+// One layer of a filter_bands run: its output height, kernel height, the alignment
+// of the band starts and the kernel `run(band, band_height)`.
+struct BandLayer {
+    height: usize,
+    kh: usize,
+    align: usize,
+    run: Box<Fn(&mut Vec<f32>, usize) + Send + Sync>,
+}
+
+impl BandLayer {
+    fn new<F>(height: usize, kh: usize, align: usize, run: F) -> BandLayer
+        where F: Fn(&mut Vec<f32>, usize) + Send + Sync + 'static
+    {
+        BandLayer { height: height, kh: kh, align: align, run: Box::new(run) }
+    }
+}
+
+// Runs `layers` on `buf` with one worker thread per band; the workers are spawned once
+// and meet at a barrier after every layer. A layer's output rows are split into one band
+// per thread, copied together with their (kH - 1)-row halo and written back into `buf`
+// once every worker is done reading it. Band starts are multiples of the layer's `align`
+// so kernels working on row tiles see the same tiling as one thread.
+fn filter_bands(buf: Vec<f32>, stride: usize, threads: usize, layers: Vec<BandLayer>,
+                perf: &mut PerfStatus) -> Vec<f32> {
+    if perf.thread_time.len() < threads {
+        perf.thread_time.resize(threads, 0.0);
+    }
+    let layers = Arc::new(layers);
+    let shared = Arc::new(RwLock::new(buf));
+    let barrier = Arc::new(Barrier::new(threads));
+    let mut handles = Vec::with_capacity(threads);
+    for t in 0..threads {
+        let (layers, shared, barrier) = (layers.clone(), shared.clone(), barrier.clone());
+        handles.push(thread::spawn(move || {
+            let mut elapsed = 0.0;
+            let mut failure = None;
+            for layer in layers.iter() {
+                let start = time::precise_time_s();
+                let rows_per_thread = ((layer.height + threads - 1) / threads + layer.align - 1)
+                    / layer.align * layer.align;
+                let y_begin = std::cmp::min(layer.height, t * rows_per_thread);
+                let y_end = std::cmp::min(layer.height, y_begin + rows_per_thread);
+                let mut band = Vec::new();
+                if y_begin < y_end && failure.is_none() {
+                    band = shared.read().unwrap()[y_begin * stride..(y_end + layer.kh - 1) * stride].to_vec();
+                    // a panicking worker keeps meeting the barrier so the others do not hang
+                    let run = panic::catch_unwind(panic::AssertUnwindSafe(|| (layer.run)(&mut band, y_end - y_begin)));
+                    match run {
+                        Ok(_) => band.truncate((y_end - y_begin) * stride),
+                        Err(e) => { failure = Some(e); band.clear(); },
+                    }
+                }
+                elapsed += time::precise_time_s() - start;
+                barrier.wait();
+                if !band.is_empty() {
+                    shared.write().unwrap()[y_begin * stride..y_begin * stride + band.len()].copy_from_slice(&band);
+                }
+                barrier.wait();
+            }
+            match failure {
+                Some(e) => panic::resume_unwind(e),
+                None => elapsed,
+            }
+        }));
+    }
+
+    for (t, handle) in handles.into_iter().enumerate() {
+        perf.thread_time[t] += handle.join().unwrap();
+    }
+    Arc::try_unwrap(shared).ok().expect("band threads have finished").into_inner().unwrap()
+}
+
+// c = a * b for `m_panels` GEMM_MR-row panels of `a` (laid out as by gemm_im2col),
+// c is row-major with b.panels * GEMM_NR columns.
+fn gemm_multiply(a: &[f32], m_panels: usize, b: &GemmWeights, c: &mut [f32]) {
+    let (k, n) = (b.k, b.panels * GEMM_NR);
+    for v in c[..m_panels * GEMM_MR * n].iter_mut() {
+        *v = 0.0;
+    }
+    let mut pc = 0;
+    while pc < k {
+        let kc = std::cmp::min(GEMM_KC, k - pc);
+        for jp in 0..b.panels {
+            let bp = &b.data[(jp * k + pc) * GEMM_NR..];
+            for ip in 0..m_panels {
+                gemm_micro_kernel(kc, &a[(ip * k + pc) * GEMM_MR..], bp,
+                                  c, ip * GEMM_MR * n + jp * GEMM_NR, n);
+            }
+        }
+        pc += kc;
+    }
+}
+
+// Lowers the `m` output pixels starting `first` pixels into the band at row y0 into
+// GEMM_MR-pixel panels, each stored k-major (a[(panel * k + kk) * GEMM_MR + r]).
+// The padding pixels of the last panel are zero.
+fn gemm_im2col(buf: &[f32], num_in: usize, kw: usize, kh: usize, width: usize, stride: usize,
+               y0: usize, first: usize, m: usize, k: usize, a: &mut [f32]) {
+    let m_padded = (m + GEMM_MR - 1) / GEMM_MR * GEMM_MR;
+    let row_len = kw * num_in;
+    unsafe {
+        for idx in 0..m_padded {
+            let dst = (idx / GEMM_MR) * k * GEMM_MR + idx % GEMM_MR;
+            if idx >= m {
+                for kk in 0..k {
+                    *a.get_unchecked_mut(dst + kk * GEMM_MR) = 0.0;
+                }
+                continue;
+            }
+            let (y, x) = (y0 + (first + idx) / width, (first + idx) % width);
+            for ky in 0..kh {
+                let src = (y + ky) * stride + x * num_in;
+                let k_off = ky * row_len;
+                for t in 0..row_len {
+                    *a.get_unchecked_mut(dst + (k_off + t) * GEMM_MR) = *buf.get_unchecked(src + t);
+                }
+            }
+        }
+    }
+}
+
+// c[c_off + r * ldc + j] += sum_p a[p * GEMM_MR + r] * b[p * GEMM_NR + j]
+// for a GEMM_MR x GEMM_NR tile, accumulated in registers over the K block.
+#[inline(always)]
+fn gemm_micro_kernel(kc: usize, a: &[f32], b: &[f32], c: &mut [f32], c_off: usize, ldc: usize) {
+    let mut acc = [0.0f32; GEMM_MR * GEMM_NR];
+    unsafe {
+        for p in 0..kc {
+            let ap = a.get_unchecked(p * GEMM_MR..);
+            let bp = b.get_unchecked(p * GEMM_NR..);
+            for r in 0..GEMM_MR {
+                let av = *ap.get_unchecked(r);
+                for j in 0..GEMM_NR {
+                    *acc.get_unchecked_mut(r * GEMM_NR + j) += av * *bp.get_unchecked(j);
+                }
+            }
+        }
+        for r in 0..GEMM_MR {
+            for j in 0..GEMM_NR {
+                *c.get_unchecked_mut(c_off + r * ldc + j) += *acc.get_unchecked(r * GEMM_NR + j);
+            }
+        }
+    }
+}
+
+/// Output tile of the Winograd transform: F(2x2, 3x3) multiplies 2.25x less than a
+/// direct 3x3 convolution, F(4x4, 3x3) 4x less at a slightly larger rounding error.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WinogradTile {
+    F2x2,
+    F4x4,
+}
+
+// B^T, G and A^T of F(m x m, 3 x 3) (Lavin & Gray), row-major, alpha = m + 2.
+struct WinogradTransform {
+    m: usize,
+    bt: &'static [f32],
+    g: &'static [f32],
+    at: &'static [f32],
+}
+
+const WINOGRAD_F2X2: WinogradTransform = WinogradTransform {
+    m: 2,
+    bt: &[1.0, 0.0, -1.0, 0.0,
+          0.0, 1.0, 1.0, 0.0,
+          0.0, -1.0, 1.0, 0.0,
+          0.0, 1.0, 0.0, -1.0],
+    g: &[1.0, 0.0, 0.0,
+         0.5, 0.5, 0.5,
+         0.5, -0.5, 0.5,
+         0.0, 0.0, 1.0],
+    at: &[1.0, 1.0, 1.0, 0.0,
+          0.0, 1.0, -1.0, -1.0],
+};
+
+const WINOGRAD_F4X4: WinogradTransform = WinogradTransform {
+    m: 4,
+    bt: &[4.0, 0.0, -5.0, 0.0, 1.0, 0.0,
+          0.0, -4.0, -4.0, 1.0, 1.0, 0.0,
+          0.0, 4.0, -4.0, -1.0, 1.0, 0.0,
+          0.0, -2.0, -1.0, 2.0, 1.0, 0.0,
+          0.0, 2.0, -1.0, -2.0, 1.0, 0.0,
+          0.0, 4.0, 0.0, -5.0, 0.0, 1.0],
+    g: &[1.0 / 4.0, 0.0, 0.0,
+         -1.0 / 6.0, -1.0 / 6.0, -1.0 / 6.0,
+         -1.0 / 6.0, 1.0 / 6.0, -1.0 / 6.0,
+         1.0 / 24.0, 1.0 / 12.0, 1.0 / 6.0,
+         1.0 / 24.0, -1.0 / 12.0, 1.0 / 6.0,
+         0.0, 0.0, 1.0],
+    at: &[1.0, 1.0, 1.0, 1.0, 1.0, 0.0,
+          0.0, 1.0, -1.0, 2.0, -2.0, 0.0,
+          0.0, 1.0, 1.0, 4.0, 4.0, 0.0,
+          0.0, 1.0, -1.0, 8.0, -8.0, 1.0],
+};
+
+impl WinogradTile {
+    fn transform(&self) -> &'static WinogradTransform {
+        match *self {
+            WinogradTile::F2x2 => &WINOGRAD_F2X2,
+            WinogradTile::F4x4 => &WINOGRAD_F4X4,
+        }
+    }
+}
+
+/// Weights of a layer prepared for filter_winograd.
+pub enum WinogradWeights {
+    /// G g G^T of every kernel, as alpha x alpha nInputPlane x nOutputPlane matrices
+    Transformed(WinogradTile, Vec<GemmWeights>),
+    /// layers other than 3x3 fall back to the filter_cpu2 kernels (model::pack_weights)
+    Direct(Vec<Vec<f32>>),
+}
+
+pub fn winograd_pack_weights(layer: &Layer, tile: WinogradTile) -> WinogradWeights {
+    if layer.kW != 3 || layer.kH != 3 {
+        return WinogradWeights::Direct(model::pack_weights(layer));
+    }
+    let tr = tile.transform();
+    let alpha = tr.m + 2;
+    let (num_in, num_out) = (layer.nInputPlane as usize, layer.nOutputPlane as usize);
+
+    // u[((o * num_in + i) * alpha + a) * alpha + b] = (G g G^T)[a][b], computed in f64
+    let mut u = vec![0.0f32; num_out * num_in * alpha * alpha];
+    for o in 0..num_out {
+        for i in 0..num_in {
+            let g = &layer.weight[o][i];
+            let mut gg = vec![0.0f64; alpha * 3];
+            for a in 0..alpha {
+                for kx in 0..3 {
+                    for ky in 0..3 {
+                        gg[a * 3 + kx] += tr.g[a * 3 + ky] as f64 * g[ky][kx] as f64;
+                    }
+                }
+            }
+            for a in 0..alpha {
+                for b in 0..alpha {
+                    let mut v = 0.0f64;
+                    for kx in 0..3 {
+                        v += gg[a * 3 + kx] * tr.g[b * 3 + kx] as f64;
+                    }
+                    u[((o * num_in + i) * alpha + a) * alpha + b] = v as f32;
+                }
+            }
+        }
+    }
+    let matrices = (0..alpha * alpha).map(|e| {
+        gemm_pack_matrix(num_in, num_out, |i, o| u[(o * num_in + i) * alpha * alpha + e])
+    }).collect();
+    WinogradWeights::Transformed(tile, matrices)
+}
+
+/// Runs the model with Winograd F(m x m, 3 x 3) convolutions: every tile row is
+/// transformed, multiplied with the transformed weights (one matrix product per
+/// transform element) and transformed back. Layers prepared as WinogradWeights::Direct
+/// run through the filter_cpu2 kernels. With `threads` > 1 the rows are split like
+/// filter_cpu2_mt along tile boundaries, so the result matches the single-threaded one.
+pub fn filter_winograd(in_img: Image, model: &Model, weights: &[Arc<WinogradWeights>], threads: usize,
+                       perf: &mut PerfStatus) -> Image {
+    check_input(&in_img, model);
+    assert!(weights.len() == model.len(), "{} packed layers for a {} layer model", weights.len(), model.len());
+    for (i, (layer, w)) in model.iter().zip(weights.iter()).enumerate() {
+        match **w {
+            WinogradWeights::Transformed(tile, ref u) => {
+                let alpha = tile.transform().m + 2;
+                assert!(layer.kW == 3 && layer.kH == 3 && u.len() == alpha * alpha &&
+                        u.iter().all(|m| m.fits(layer.nInputPlane as usize, layer.nOutputPlane as usize)),
+                        "layer {}: winograd weights do not match its shape", i);
+            },
+            WinogradWeights::Direct(ref w) => check_direct_weights(i, layer, w),
+        }
+    }
+    let threads = std::cmp::max(1, threads);
+    let stride = filter_cpu2_stride(in_img.width, model);
+    let mut buf = filter_cpu2_pack(&in_img, model[0].nInputPlane as usize, stride);
+    let (mut width, mut height) = (in_img.width, in_img.height);
+
+    let mut layers = Vec::new();
+    for (layer, weights) in model.iter().zip(weights.iter()) {
+        let (kw, kh) = (layer.kW as usize, layer.kH as usize);
+        width -= kw - 1;
+        height -= kh - 1;
+        perf.cnn_flo += layer_flops(layer, width, height);
+
+        let num_in = layer.nInputPlane as usize;
+        let num_out = layer.nOutputPlane as usize;
+        if threads == 1 {
+            filter_winograd_layer_any(weights, &layer.bias, num_in, num_out, kw, kh, width, height,
+                                      stride, &mut buf);
+            continue;
+        }
+        let align = match **weights {
+            WinogradWeights::Transformed(tile, _) => tile.transform().m,
+            WinogradWeights::Direct(_) => 1,
+        };
+        let (weights, bias) = (weights.clone(), layer.bias.clone());
+        layers.push(BandLayer::new(height, kh, align, move |band, band_height| {
+            filter_winograd_layer_any(&weights, &bias, num_in, num_out, kw, kh, width, band_height,
+                                      stride, band);
+        }));
+    }
+    if threads > 1 {
+        buf = filter_bands(buf, stride, threads, layers, perf);
+    }
+
+    filter_cpu2_unpack(&buf, model[model.len() - 1].nOutputPlane as usize,
+                       width, height, stride, &in_img)
+}
+
+/// Number of bytes filter_winograd allocates when processing a `width` x `height` padded input.
+pub fn filter_winograd_buffer_size(width: usize, height: usize, model: &Model, tile: WinogradTile,
+                                   threads: usize) -> usize {
+    let tr = tile.transform();
+    let alpha = tr.m + 2;
+    let tiles = ((width + tr.m - 1) / tr.m + GEMM_MR - 1) / GEMM_MR * GEMM_MR;
+    let mut scratch = 0;
+    for layer in model.iter() {
+        let n = (layer.nOutputPlane as usize + GEMM_NR - 1) / GEMM_NR * GEMM_NR;
+        let planes = layer.nInputPlane as usize + n;
+        scratch = std::cmp::max(scratch, alpha * alpha * tiles * planes * std::mem::size_of::<f32>());
+    }
+    filter_cpu2_buffer_size(width, height, model, threads) + scratch * threads
+}
+
+fn filter_winograd_layer_any(weights: &WinogradWeights, bias: &[f32], num_in: usize, num_out: usize,
+                             kw: usize, kh: usize, width: usize, height: usize, stride: usize,
+                             buf: &mut Vec<f32>) {
+    match *weights {
+        WinogradWeights::Transformed(tile, ref u) => {
+            filter_winograd_layer(tile.transform(), u, bias, num_in, num_out, width, height, stride, buf);
+        },
+        WinogradWeights::Direct(ref w) => {
+            let mut temp: Vec<f32> = vec![0.0; num_out];
+            let mut out_line: Vec<f32> = vec![0.0; stride];
+            filter_cpu2_layer_any(w, bias, num_in, num_out, kw, kh, width, height, stride,
+                                  &mut temp, buf, &mut out_line);
+        },
+    }
+}
+
+// Same in-place contract as filter_cpu2_layer_any, processed one row of m x m output
+// tiles at a time. Tiles reaching past the input read zeros; their extra outputs are dropped.
+fn filter_winograd_layer(tr: &WinogradTransform, u: &[GemmWeights], bias: &[f32], num_in: usize,
+                         num_out: usize, width: usize, height: usize, stride: usize, buf: &mut Vec<f32>) {
+    let (m, alpha) = (tr.m, tr.m + 2);
+    let (in_width, in_height) = (width + 2, height + 2);
+    let n = u[0].panels * GEMM_NR;
+    let tiles = (width + m - 1) / m;
+    let m_panels = (tiles + GEMM_MR - 1) / GEMM_MR;
+    let a_size = m_panels * GEMM_MR * num_in;
+    let c_size = m_panels * GEMM_MR * n;
+    // per transform element e: tiles x num_in input (gemm_im2col layout) and tiles x n product
+    let mut a: Vec<f32> = vec![0.0; alpha * alpha * a_size];
+    let mut c: Vec<f32> = vec![0.0; alpha * alpha * c_size];
+    let mut d: Vec<f32> = vec![0.0; alpha * alpha * num_in];
+    let mut tmp: Vec<f32> = vec![0.0; alpha * alpha * std::cmp::max(num_in, num_out)];
+    let mut half: Vec<f32> = vec![0.0; alpha * alpha * std::cmp::max(num_in, num_out)];
+    let mut y_out: Vec<f32> = vec![0.0; m * m * num_out];
+
+    let mut y0 = 0;
+    while y0 < height {
+        unsafe {
+            // input transform B^T d B of every tile, vectorized over the input planes
+            for t in 0..tiles {
+                let x0 = t * m;
+                for r in 0..alpha {
+                    for col in 0..alpha {
+                        let dst = (r * alpha + col) * num_in;
+                        let (y, x) = (y0 + r, x0 + col);
+                        for i in 0..num_in {
+                            *d.get_unchecked_mut(dst + i) = if y < in_height && x < in_width {
+                                *buf.get_unchecked(y * stride + x * num_in + i)
+                            } else {
+                                0.0
+                            };
+                        }
+                    }
+                }
+                winograd_transform(tr.bt, alpha, alpha, num_in, &d, &mut half, &mut tmp);
+                let a_off = (t / GEMM_MR) * num_in * GEMM_MR + t % GEMM_MR;
+                for e in 0..alpha * alpha {
+                    let src = e * num_in;
+                    let dst = e * a_size + a_off;
+                    for i in 0..num_in {
+                        *a.get_unchecked_mut(dst + i * GEMM_MR) = *tmp.get_unchecked(src + i);
+                    }
+                }
+            }
+
+            for e in 0..alpha * alpha {
+                gemm_multiply(&a[e * a_size..], m_panels, &u[e], &mut c[e * c_size..]);
+            }
+
+            // output transform A^T M A, bias and leaky ReLU
+            let rows = std::cmp::min(m, height - y0);
+            for t in 0..tiles {
+                for e in 0..alpha * alpha {
+                    let src = e * c_size + t * n;
+                    for o in 0..num_out {
+                        *tmp.get_unchecked_mut(e * num_out + o) = *c.get_unchecked(src + o);
+                    }
+                }
+                winograd_transform(tr.at, m, alpha, num_out, &tmp, &mut half, &mut y_out);
+                let x0 = t * m;
+                let cols = std::cmp::min(m, width - x0);
+                for r in 0..rows {
+                    for col in 0..cols {
+                        let src = (r * m + col) * num_out;
+                        let dst = (y0 + r) * stride + (x0 + col) * num_out;
+                        for o in 0..num_out {
+                            let mut v = *y_out.get_unchecked(src + o) + *bias.get_unchecked(o);
+                            if v < 0.0 {
+                                v *= 0.1;
+                            }
+                            *buf.get_unchecked_mut(dst + o) = v;
+                        }
+                    }
+                }
+            }
+        }
+        y0 += m;
+    }
+}
+
+// out = T x T^T for a `rows` x `cols` matrix T applied to a cols x cols tile of
+// `planes`-vectors (x[(r * cols + c) * planes + i]); out is rows x rows, `half` is scratch.
+fn winograd_transform(t: &[f32], rows: usize, cols: usize, planes: usize, x: &[f32],
+                      half: &mut [f32], out: &mut [f32]) {
+    unsafe {
+        // half = T x
+        for v in half[..rows * cols * planes].iter_mut() {
+            *v = 0.0;
+        }
+        for r in 0..rows {
+            for k in 0..cols {
+                let coef = *t.get_unchecked(r * cols + k);
+                if coef == 0.0 {
+                    continue;
+                }
+                for col in 0..cols {
+                    let (src, dst) = ((k * cols + col) * planes, (r * cols + col) * planes);
+                    for i in 0..planes {
+                        *half.get_unchecked_mut(dst + i) += coef * *x.get_unchecked(src + i);
+                    }
+                }
+            }
+        }
+        // out = half T^T
+        for v in out[..rows * rows * planes].iter_mut() {
+            *v = 0.0;
+        }
+        for r in 0..rows {
+            for col in 0..rows {
+                let dst = (r * rows + col) * planes;
+                for k in 0..cols {
+                    let coef = *t.get_unchecked(col * cols + k);
+                    if coef == 0.0 {
+                        continue;
+                    }
+                    let src = (r * cols + k) * planes;
+                    for i in 0..planes {
+                        *out.get_unchecked_mut(dst + i) += coef * *half.get_unchecked(src + i);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std;
+use std::fmt;
+use std::io;
+
+use piston_image::ImageError;
+use png;
+use model::LoadModelError;
+
+#[derive(Debug)]
+pub enum Error {
+    IOError(io::Error),
+    ImageError(ImageError),
+    ModelError(LoadModelError),
+    UnsupportedFormat(String),
+    DimensionError(String),
+    InvalidArgument(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::IOError(ref e) => write!(f, "I/O error: {}", e),
+            Error::ImageError(ref e) => write!(f, "image error: {}", e),
+            Error::ModelError(ref e) => write!(f, "model error: {}", e),
+            Error::UnsupportedFormat(ref s) => write!(f, "unsupported format: {}", s),
+            Error::DimensionError(ref s) => write!(f, "invalid dimensions: {}", s),
+            Error::InvalidArgument(ref s) => write!(f, "invalid argument: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::IOError(_) => "I/O error",
+            Error::ImageError(_) => "image error",
+            Error::ModelError(_) => "model error",
+            Error::UnsupportedFormat(_) => "unsupported format",
+            Error::DimensionError(_) => "invalid dimensions",
+            Error::InvalidArgument(_) => "invalid argument",
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::IOError(e)
+    }
+}
+
+impl From<ImageError> for Error {
+    fn from(e: ImageError) -> Error {
+        Error::ImageError(e)
+    }
+}
+
+impl From<LoadModelError> for Error {
+    fn from(e: LoadModelError) -> Error {
+        Error::ModelError(e)
+    }
+}
+
+impl From<png::DecodingError> for Error {
+    fn from(e: png::DecodingError) -> Error {
+        match e {
+            png::DecodingError::IoError(e) => Error::IOError(e),
+            e => Error::ImageError(ImageError::FormatError(format!("{}", e))),
+        }
+    }
+}
+
+impl From<png::EncodingError> for Error {
+    fn from(e: png::EncodingError) -> Error {
+        match e {
+            png::EncodingError::IoError(e) => Error::IOError(e),
+            e => Error::ImageError(ImageError::FormatError(format!("{}", e))),
+        }
+    }
+}
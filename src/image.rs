@@ -1,13 +1,24 @@
 use std;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
 
 extern crate image as piston_image;
 use piston_image::GenericImage;
+use png;
+use png::HasParameters;
+
+use error::{Error, Result};
 
 #[derive(Clone)]
 pub struct Image {
     pub width: usize,
     pub height: usize,
     pub color_space: ColorSpace,
+    /// the last plane of `data` is an alpha plane
+    pub has_alpha: bool,
+    /// sample format of the source, used when saving
+    pub sample_format: SampleFormat,
     pub data: Vec<Vec<f32>>,
     pub strides: Vec<usize>,
 }
@@ -16,109 +27,396 @@ pub struct Image {
 pub enum ColorSpace {
     RGB = 0,
     I444 = 1,
+    Gray = 2,
+}
+
+/// Resampling filter used by Image::scale2x and Image::resize.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Interpolation {
+    Nearest,
+    Bilinear,
+    /// Catmull-Rom (a = -0.5)
+    Bicubic,
+    Lanczos3,
+}
+
+impl Interpolation {
+    fn support(self) -> f32 {
+        match self {
+            Interpolation::Nearest => 0.5,
+            Interpolation::Bilinear => 1.0,
+            Interpolation::Bicubic => 2.0,
+            Interpolation::Lanczos3 => 3.0,
+        }
+    }
+
+    fn kernel(self, x: f32) -> f32 {
+        let x = x.abs();
+        match self {
+            Interpolation::Nearest => if x < 0.5 { 1.0 } else { 0.0 },
+            Interpolation::Bilinear => if x < 1.0 { 1.0 - x } else { 0.0 },
+            Interpolation::Bicubic => {
+                if x < 1.0 {
+                    (1.5 * x - 2.5) * x * x + 1.0
+                } else if x < 2.0 {
+                    ((-0.5 * x + 2.5) * x - 4.0) * x + 2.0
+                } else {
+                    0.0
+                }
+            },
+            Interpolation::Lanczos3 => {
+                if x == 0.0 {
+                    return 1.0;
+                }
+                if x >= 3.0 {
+                    return 0.0;
+                }
+                let px = std::f32::consts::PI * x;
+                3.0 * px.sin() * (px / 3.0).sin() / (px * px)
+            },
+        }
+    }
+}
+
+/// How Image::add_padding fills the border (shown for a row `abcd`).
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum PaddingMode {
+    /// aa|abcd|dd
+    Replicate,
+    /// ba|abcd|dc
+    Reflect,
+    /// cb|abcd|cb
+    Mirror101,
+    /// 00|abcd|00
+    Zero,
+    /// cd|abcd|ab, for tileable textures
+    Wrap,
+}
+
+impl PaddingMode {
+    // maps a coordinate outside of 0..len back into the image, None for zero padding
+    fn map(self, v: isize, len: usize) -> Option<usize> {
+        let n = len as isize;
+        if v >= 0 && v < n {
+            return Some(v as usize);
+        }
+        let modulo = |a: isize, b: isize| ((a % b) + b) % b;
+        match self {
+            PaddingMode::Replicate => Some(if v < 0 { 0 } else { len - 1 }),
+            PaddingMode::Reflect => {
+                let m = modulo(v, 2 * n);
+                Some((if m < n { m } else { 2 * n - 1 - m }) as usize)
+            },
+            PaddingMode::Mirror101 => {
+                if n == 1 {
+                    return Some(0);
+                }
+                let m = modulo(v, 2 * n - 2);
+                Some((if m < n { m } else { 2 * n - 2 - m }) as usize)
+            },
+            PaddingMode::Zero => None,
+            PaddingMode::Wrap => Some(modulo(v, n) as usize),
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum SampleFormat {
+    U8,
+    U16,
+    F32,
 }
 
 impl Image {
+    pub fn new(width: usize, height: usize, channels: usize, color_space: ColorSpace) -> Image {
+        Image {
+            width: width,
+            height: height,
+            color_space: color_space,
+            has_alpha: false,
+            sample_format: SampleFormat::U8,
+            data: vec![vec![0.0; width * height]; channels],
+            strides: vec![width; channels],
+        }
+    }
+
     pub fn from_dynamic_image(img: &piston_image::DynamicImage) -> Image {
-        let rgb_img = img.to_rgb();
+        let (src, channels, color_space, has_alpha) = match *img {
+            piston_image::DynamicImage::ImageLuma8(_) =>
+                (img.to_luma().into_raw(), 1, ColorSpace::Gray, false),
+            piston_image::DynamicImage::ImageLumaA8(_) =>
+                (img.to_luma_alpha().into_raw(), 2, ColorSpace::Gray, true),
+            piston_image::DynamicImage::ImageRgba8(_) =>
+                (img.to_rgba().into_raw(), 4, ColorSpace::RGB, true),
+            _ => (img.to_rgb().into_raw(), 3, ColorSpace::RGB, false),
+        };
         let w = img.width() as usize;
         let h = img.height() as usize;
         let vec_size = w * h;
-        let mut r: Vec<f32> = Vec::with_capacity(vec_size);
-        let mut g: Vec<f32> = Vec::with_capacity(vec_size);
-        let mut b: Vec<f32> = Vec::with_capacity(vec_size);
-        unsafe {
-            r.set_len(vec_size);
-            g.set_len(vec_size);
-            b.set_len(vec_size);
-        }
-        let src = rgb_img.into_raw();
-        for y in 0..h {
-            let off = y * w;
-            for x in 0..w {
-                r[off + x] = (src[(off + x) * 3 + 0] as f32) / 255.0;
-                g[off + x] = (src[(off + x) * 3 + 1] as f32) / 255.0;
-                b[off + x] = (src[(off + x) * 3 + 2] as f32) / 255.0;
+        let mut data: Vec<Vec<f32>> = Vec::with_capacity(channels);
+        for k in 0..channels {
+            let mut v: Vec<f32> = Vec::with_capacity(vec_size);
+            unsafe { v.set_len(vec_size); }
+            for i in 0..vec_size {
+                v[i] = (src[i * channels + k] as f32) / 255.0;
             }
+            data.push(v);
         }
         Image {
             width: w,
             height: h,
-            color_space: ColorSpace::RGB,
-            data: vec![r, g, b],
-            strides: vec![w, w, w],
+            color_space: color_space,
+            has_alpha: has_alpha,
+            sample_format: SampleFormat::U8,
+            data: data,
+            strides: vec![w; channels],
         }
     }
 
     pub fn to_dynamic_image(&self) -> piston_image::DynamicImage {
-        if self.color_space != ColorSpace::RGB {
+        if self.color_space == ColorSpace::I444 {
             let mut tmp_img = self.clone();
             tmp_img.change_colorspace_rgb();
             return tmp_img.to_dynamic_image();
         }
 
-        let mut dimg = piston_image::DynamicImage::new_rgb8(self.width as u32,
-                                                            self.height as u32);
-        let s0 = &self.data[0];
-        let s1 = &self.data[1];
-        let s2 = &self.data[2];
+        let channels = self.data.len();
+        let mut raw: Vec<u8> = Vec::with_capacity(self.width * self.height * channels);
         for i in 0..self.height {
-            let off = i * self.strides[0];
             for j in 0..self.width {
-                let r = std::cmp::min(255, std::cmp::max(0, (s0[off + j] * 255.0) as i32));
-                let g = std::cmp::min(255, std::cmp::max(0, (s1[off + j] * 255.0) as i32));
-                let b = std::cmp::min(255, std::cmp::max(0, (s2[off + j] * 255.0) as i32));
-                dimg.put_pixel(j as u32, i as u32, piston_image::Rgba {
-                    data: [r as u8, g as u8, b as u8, 0] 
-                });
+                for k in 0..channels {
+                    let v = self.data[k][i * self.strides[k] + j];
+                    raw.push(std::cmp::min(255, std::cmp::max(0, (v * 255.0) as i32)) as u8);
+                }
             }
         }
-        dimg
+        let (w, h) = (self.width as u32, self.height as u32);
+        match channels {
+            1 => piston_image::DynamicImage::ImageLuma8(piston_image::ImageBuffer::from_raw(w, h, raw).unwrap()),
+            2 => piston_image::DynamicImage::ImageLumaA8(piston_image::ImageBuffer::from_raw(w, h, raw).unwrap()),
+            3 => piston_image::DynamicImage::ImageRgb8(piston_image::ImageBuffer::from_raw(w, h, raw).unwrap()),
+            _ => piston_image::DynamicImage::ImageRgba8(piston_image::ImageBuffer::from_raw(w, h, raw).unwrap()),
+        }
+    }
+
+    /// Removes the alpha plane and returns it as a single-plane image.
+    pub fn take_alpha(&mut self) -> Option<Image> {
+        if !self.has_alpha {
+            return None;
+        }
+        self.has_alpha = false;
+        let plane = self.data.pop().unwrap();
+        let stride = self.strides.pop().unwrap();
+        Some(Image {
+            width: self.width,
+            height: self.height,
+            color_space: ColorSpace::RGB,
+            has_alpha: false,
+            sample_format: self.sample_format,
+            data: vec![plane],
+            strides: vec![stride],
+        })
     }
 
-    pub fn scale2x(&self) -> Image {
+    /// Appends the first plane of `alpha` as the alpha plane.
+    pub fn set_alpha(&mut self, alpha: Image) {
+        assert!(!self.has_alpha);
+        assert!(alpha.width == self.width && alpha.height == self.height);
+        let mut alpha = alpha;
+        self.data.push(alpha.data.swap_remove(0));
+        self.strides.push(alpha.strides[0]);
+        self.has_alpha = true;
+    }
+
+    /// Doubles the size of the image. `chroma_filter` is used for the U/V
+    /// planes of I444 images, `filter` for every other plane.
+    pub fn scale2x(&self, filter: Interpolation, chroma_filter: Interpolation) -> Image {
+        let (width, height) = (self.width * 2, self.height * 2);
         let mut data: Vec<Vec<f32>> = Vec::with_capacity(self.data.len());
+        for k in 0..self.data.len() {
+            let chroma = self.color_space == ColorSpace::I444 && (k == 1 || k == 2);
+            let f = if chroma { chroma_filter } else { filter };
+            if f == Interpolation::Nearest {
+                data.push(self.scale2x_nearest_plane(k));
+            } else {
+                data.push(self.resample_plane(k, width, height, f));
+            }
+        }
+
+        Image {
+            width: width,
+            height: height,
+            color_space: self.color_space.clone(),
+            has_alpha: self.has_alpha,
+            sample_format: self.sample_format,
+            strides: vec![width; data.len()],
+            data: data,
+        }
+    }
+
+    fn scale2x_nearest_plane(&self, k: usize) -> Vec<f32> {
+        let v = &self.data[k];
         let stride = self.width * 2;
         let stride_h = self.height * 2;
+        let mut x: Vec<f32> = Vec::with_capacity(stride * stride_h);
+        unsafe { x.set_len(stride * stride_h); }
+        for y in 0..self.height {
+            let off_src = y * self.strides[k];
+            let off_dst = y * stride * 2;
+            for i in 0..self.width {
+                let t = v[off_src + i];
+                x[off_dst + i * 2 + 0] = t;
+                x[off_dst + i * 2 + 1] = t;
+                x[off_dst + stride + i * 2 + 0] = t;
+                x[off_dst + stride + i * 2 + 1] = t;
+            }
+        }
+        x
+    }
 
-        for v in self.data.iter() {
-            let mut x: Vec<f32> = Vec::with_capacity(stride * stride_h);
-            unsafe { x.set_len(stride * stride_h); }
-            for y in 0..self.height {
-                let off_src = y * self.strides[0];
-                let off_dst = y * stride * 2;
-                for i in 0..self.width {
-                    let t = v[off_src + i];
-                    x[off_dst + i * 2 + 0] = t;
-                    x[off_dst + i * 2 + 1] = t;
-                    x[off_dst + stride + i * 2 + 0] = t;
-                    x[off_dst + stride + i * 2 + 1] = t;
+    pub fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Image {
+        let mut data: Vec<Vec<f32>> = Vec::with_capacity(self.data.len());
+        for k in 0..self.data.len() {
+            let v = &self.data[k];
+            let mut out: Vec<f32> = Vec::with_capacity(width * height);
+            for i in 0..height {
+                let off = (y + i) * self.strides[k] + x;
+                out.extend_from_slice(&v[off..off + width]);
+            }
+            data.push(out);
+        }
+
+        Image {
+            width: width,
+            height: height,
+            color_space: self.color_space.clone(),
+            has_alpha: self.has_alpha,
+            sample_format: self.sample_format,
+            data: data,
+            strides: vec![width; self.data.len()],
+        }
+    }
+
+    /// Swaps the x and y axes.
+    pub fn transpose(&self) -> Image {
+        let (width, height) = (self.height, self.width);
+        self.remap(width, height, |x, y| (y, x))
+    }
+
+    /// Mirrors the image left to right.
+    pub fn flip_horizontal(&self) -> Image {
+        let w = self.width;
+        self.remap(self.width, self.height, |x, y| (w - 1 - x, y))
+    }
+
+    /// Mirrors the image top to bottom.
+    pub fn flip_vertical(&self) -> Image {
+        let h = self.height;
+        self.remap(self.width, self.height, |x, y| (x, h - 1 - y))
+    }
+
+    // builds a width x height image where (x, y) is taken from src(x, y) of self
+    fn remap<F: Fn(usize, usize) -> (usize, usize)>(&self, width: usize, height: usize, src: F) -> Image {
+        let mut data: Vec<Vec<f32>> = Vec::with_capacity(self.data.len());
+        for k in 0..self.data.len() {
+            let v = &self.data[k];
+            let mut out: Vec<f32> = Vec::with_capacity(width * height);
+            for y in 0..height {
+                for x in 0..width {
+                    let (sx, sy) = src(x, y);
+                    out.push(v[sy * self.strides[k] + sx]);
                 }
             }
-            data.push(x);
+            data.push(out);
+        }
+
+        Image {
+            width: width,
+            height: height,
+            color_space: self.color_space.clone(),
+            has_alpha: self.has_alpha,
+            sample_format: self.sample_format,
+            data: data,
+            strides: vec![width; self.data.len()],
+        }
+    }
+
+    pub fn paste(&mut self, src: &Image, x: usize, y: usize) {
+        for k in 0..self.data.len() {
+            let dst_stride = self.strides[k];
+            let src_stride = src.strides[k];
+            for i in 0..src.height {
+                let off_dst = (y + i) * dst_stride + x;
+                let off_src = i * src_stride;
+                self.data[k][off_dst..off_dst + src.width]
+                    .copy_from_slice(&src.data[k][off_src..off_src + src.width]);
+            }
         }
+    }
+
+    /// Resamples every plane to width x height.
+    pub fn resize(&self, width: usize, height: usize, filter: Interpolation) -> Image {
+        let data: Vec<Vec<f32>> = (0..self.data.len())
+            .map(|k| self.resample_plane(k, width, height, filter))
+            .collect();
 
         Image {
-            width: self.width * 2,
-            height: self.height * 2,
+            width: width,
+            height: height,
             color_space: self.color_space.clone(),
+            has_alpha: self.has_alpha,
+            sample_format: self.sample_format,
+            strides: vec![width; data.len()],
             data: data,
-            strides: vec![stride, stride, stride],
         }
     }
 
-    pub fn add_padding(&self, padding: usize) -> Image {
+    // separable resampling of plane k to a width x height plane
+    fn resample_plane(&self, k: usize, width: usize, height: usize, filter: Interpolation) -> Vec<f32> {
+        let wx = resample_weights(self.width, width, filter);
+        let wy = resample_weights(self.height, height, filter);
+        let v = &self.data[k];
+        let src_stride = self.strides[k];
+
+        // horizontal pass: self.height x width
+        let mut tmp = vec![0.0f32; self.height * width];
+        for y in 0..self.height {
+            let line = &v[y * src_stride..];
+            for x in 0..width {
+                let (start, ref w) = wx[x];
+                let mut sum = 0.0;
+                for i in 0..w.len() {
+                    sum += line[start + i] * w[i];
+                }
+                tmp[y * width + x] = sum;
+            }
+        }
+        // vertical pass
+        let mut out = vec![0.0f32; height * width];
+        for y in 0..height {
+            let (start, ref w) = wy[y];
+            for i in 0..w.len() {
+                let line = &tmp[(start + i) * width..];
+                for x in 0..width {
+                    out[y * width + x] += line[x] * w[i];
+                }
+            }
+        }
+        out
+    }
+
+    pub fn add_padding(&self, pad_x: usize, pad_y: usize, mode: PaddingMode) -> Image {
         let mut data: Vec<Vec<f32>> = Vec::with_capacity(self.data.len());
         let mut strides = Vec::new();
-        let stride = self.width + padding * 2;
-        let stride_h = self.height + padding * 2;
+        let stride = self.width + pad_x * 2;
+        let stride_h = self.height + pad_y * 2;
 
         for v in self.data.iter() {
             let mut x: Vec<f32> = Vec::with_capacity(stride * stride_h);
             unsafe { x.set_len(stride * stride_h); }
             for y in 0..self.height {
                 let off_src = y * self.strides[0];
-                let off_dst = (padding + y) * stride + padding;
+                let off_dst = (pad_y + y) * stride + pad_x;
                 for i in 0..self.width {
                     x[off_dst + i] = v[off_src + i];
                 }
@@ -131,51 +429,45 @@ impl Image {
             width: stride,
             height: stride_h,
             color_space: self.color_space.clone(),
+            has_alpha: self.has_alpha,
+            sample_format: self.sample_format,
             data: data,
             strides: strides,
         };
-        out.fill_padding_area(padding);
+        out.fill_padding_area(pad_x, pad_y, mode);
         out
     }
 
-    fn fill_padding_area(&mut self, padding: usize) {
+    fn fill_padding_area(&mut self, pad_x: usize, pad_y: usize, mode: PaddingMode) {
+        let (w, h) = (self.width, self.height);
+        let (inner_w, inner_h) = (w - pad_x * 2, h - pad_y * 2);
+        let src_x: Vec<Option<usize>> = (0..w)
+            .map(|j| mode.map(j as isize - pad_x as isize, inner_w).map(|v| v + pad_x))
+            .collect();
         for k in 0..self.data.len() {
             let x = &mut self.data[k];
             let stride = self.strides[k];
 
-            let off_lt = padding * stride + padding;
-            let off_rt = off_lt + self.width - padding * 2 - 1;
-            let off_lb = (self.height - padding - 1) * stride + padding;
-            let off_rb = off_lb + self.width - padding * 2 - 1;
-
-            for i in 0..self.width - padding * 2{
-                let vt = x[off_lt + i];
-                let vb = x[off_lb + i];
-                for j in 0..padding {
-                    x[j * stride + padding + i] = vt;
-                    x[off_lb + (j + 1) * stride + i] = vb;
-                }
-            }
-
-            for i in 0..self.height - padding * 2 {
-                let vl = x[off_lt + i * stride];
-                let vr = x[off_rt + i * stride];
-                for j in 0..padding {
-                    x[off_lt + i * stride - (j + 1)] = vl;
-                    x[off_rt + i * stride + (j + 1)] = vr;
-                }
-            }
-
-            let vlt = x[off_lt];
-            let vrt = x[off_rt];
-            let vlb = x[off_lb];
-            let vrb = x[off_rb];
-            for i in 0..padding {
-                for j in 0..padding {
-                    x[off_lt - (i + 1) * stride - (j + 1)] = vlt;
-                    x[off_rt - (i + 1) * stride + (j + 1)] = vrt;
-                    x[off_lb + (i + 1) * stride - (j + 1)] = vlb;
-                    x[off_rb + (i + 1) * stride + (j + 1)] = vrb;
+            for i in 0..h {
+                let off_dst = i * stride;
+                let off_src = match mode.map(i as isize - pad_y as isize, inner_h) {
+                    Some(v) => Some((v + pad_y) * stride),
+                    None => None,
+                };
+                let fill = |x: &mut Vec<f32>, j: usize| {
+                    x[off_dst + j] = match (off_src, src_x[j]) {
+                        (Some(off), Some(sx)) => x[off + sx],
+                        _ => 0.0,
+                    };
+                };
+                if i < pad_y || i >= h - pad_y {
+                    for j in 0..w {
+                        fill(x, j);
+                    }
+                } else {
+                    for j in (0..pad_x).chain(w - pad_x..w) {
+                        fill(x, j);
+                    }
                 }
             }
         }
@@ -188,21 +480,52 @@ impl Image {
         match color_space {
             ColorSpace::RGB => self.change_colorspace_rgb(),
             ColorSpace::I444 => self.change_colorspace_i444(),
+            ColorSpace::Gray => self.change_colorspace_gray(),
         }
     }
 
     pub fn change_colorspace_rgb(&mut self) {
-        if self.color_space == ColorSpace::RGB {
-            return;
+        match self.color_space {
+            ColorSpace::RGB => (),
+            ColorSpace::I444 => self._i444_to_rgb(),
+            ColorSpace::Gray => {
+                let y = self.data[0].clone();
+                self._insert_chroma(y.clone(), y);
+                self.color_space = ColorSpace::RGB;
+            },
         }
-        self._i444_to_rgb();
     }
 
     pub fn change_colorspace_i444(&mut self) {
-        if self.color_space == ColorSpace::I444 {
-            return;
+        match self.color_space {
+            ColorSpace::I444 => (),
+            ColorSpace::RGB => self._rgb_to_i444(),
+            ColorSpace::Gray => {
+                let len = self.data[0].len();
+                self._insert_chroma(vec![128.0 / 255.0; len], vec![128.0 / 255.0; len]);
+                self.color_space = ColorSpace::I444;
+            },
         }
-        self._rgb_to_i444();
+    }
+
+    pub fn change_colorspace_gray(&mut self) {
+        match self.color_space {
+            ColorSpace::Gray => return,
+            ColorSpace::RGB => self._rgb_to_i444(),
+            ColorSpace::I444 => (),
+        }
+        self.data.drain(1..3);
+        self.strides.drain(1..3);
+        self.color_space = ColorSpace::Gray;
+    }
+
+    // inserts the two chroma planes after the luma plane (and before alpha)
+    fn _insert_chroma(&mut self, u: Vec<f32>, v: Vec<f32>) {
+        let stride = self.strides[0];
+        self.data.insert(1, v);
+        self.data.insert(1, u);
+        self.strides.insert(1, stride);
+        self.strides.insert(1, stride);
     }
 
     fn _rgb_to_i444(&mut self) {
@@ -231,3 +554,396 @@ impl Image {
         self.color_space = ColorSpace::RGB;
     }
 }
+
+// For every destination pixel, the first source index and the normalized
+// filter weights. When downscaling the kernel is widened by the scale factor
+// so every source pixel contributes.
+fn resample_weights(src_len: usize, dst_len: usize, filter: Interpolation) -> Vec<(usize, Vec<f32>)> {
+    let scale = src_len as f32 / dst_len as f32;
+    let filter_scale = if scale > 1.0 { scale } else { 1.0 };
+    let support = filter.support() * filter_scale;
+    let mut result = Vec::with_capacity(dst_len);
+    for i in 0..dst_len {
+        let center = (i as f32 + 0.5) * scale;
+        let lo = std::cmp::max(0, (center - support).floor() as isize) as usize;
+        let hi = std::cmp::min(src_len, (center + support).ceil() as usize);
+        let mut w: Vec<f32> = (lo..hi)
+            .map(|j| filter.kernel((j as f32 + 0.5 - center) / filter_scale))
+            .collect();
+        let sum: f32 = w.iter().fold(0.0, |a, &b| a + b);
+        if sum != 0.0 {
+            for v in w.iter_mut() {
+                *v /= sum;
+            }
+        }
+        result.push((lo, w));
+    }
+    result
+}
+
+fn extension<P: AsRef<Path>>(path: P) -> String {
+    match path.as_ref().extension().and_then(|x| x.to_str()) {
+        Some(x) => x.to_lowercase(),
+        None => String::new(),
+    }
+}
+
+/// Checks that `path` has an extension `save` can write.
+pub fn check_path<P: AsRef<Path>>(path: P) -> Result<()> {
+    match extension(&path).as_ref() {
+        "pfm" => Ok(()),
+        "tif" | "tiff" => Err(Error::UnsupportedFormat(
+            format!("TIFF output is not supported, use .png for 16-bit output (path:{})",
+                    path.as_ref().display()))),
+        _ => format_from_path(path).map(|_| ()),
+    }
+}
+
+/// Checks that `path` has an extension `load` can read (TIFF is input only).
+pub fn check_input_path<P: AsRef<Path>>(path: P) -> Result<()> {
+    match extension(&path).as_ref() {
+        "tif" | "tiff" => Ok(()),
+        _ => check_path(path),
+    }
+}
+
+/// Loads an image keeping the precision of 16-bit PNG/TIFF and PFM sources.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Image> {
+    match extension(&path).as_ref() {
+        "pfm" => return load_pfm(&path),
+        "tif" | "tiff" => return load_tiff(&path),
+        "png" => {
+            let in_strm = try!(File::open(&path));
+            let mut decoder = png::Decoder::new(in_strm);
+            decoder.set(png::TRANSFORM_IDENTITY);
+            let (info, mut reader) = try!(decoder.read_info());
+            if info.bit_depth == png::BitDepth::Sixteen {
+                let mut buf = vec![0; info.buffer_size()];
+                try!(reader.next_frame(&mut buf));
+                return from_png16(&info, &buf);
+            }
+        },
+        _ => (),
+    }
+    let img = try!(load_dynamic_image(&path));
+    Ok(Image::from_dynamic_image(&img))
+}
+
+/// Saves an image, writing 16-bit PNG for 16-bit and float sources and PFM
+/// for `.pfm` paths.
+pub fn save<P: AsRef<Path>>(img: &Image, path: P) -> Result<()> {
+    match extension(&path).as_ref() {
+        "pfm" => save_pfm(img, &path),
+        "png" if img.sample_format != SampleFormat::U8 => save_png16(img, &path),
+        _ => save_dynamic_image(&img.to_dynamic_image(), &path),
+    }
+}
+
+fn from_png16(info: &png::OutputInfo, buf: &Vec<u8>) -> Result<Image> {
+    let (w, h) = (info.width as usize, info.height as usize);
+    let samples = info.color_type.samples();
+    let has_alpha = match info.color_type {
+        png::ColorType::RGB | png::ColorType::Grayscale => false,
+        png::ColorType::RGBA | png::ColorType::GrayscaleAlpha => true,
+        png::ColorType::Indexed => return Err(Error::UnsupportedFormat(
+            "16-bit indexed PNG".to_string())),
+    };
+    let color_space = if samples <= 2 { ColorSpace::Gray } else { ColorSpace::RGB };
+    let mut img = Image::new(w, h, samples, color_space);
+    img.has_alpha = has_alpha;
+    img.sample_format = SampleFormat::U16;
+    for y in 0..h {
+        let line = &buf[y * info.line_size..];
+        for x in 0..w {
+            for k in 0..samples {
+                let off = (x * samples + k) * 2;
+                let v = ((line[off] as u32) << 8 | line[off + 1] as u32) as f32 / 65535.0;
+                img.data[k][y * w + x] = v;
+            }
+        }
+    }
+    Ok(img)
+}
+
+fn save_png16<P: AsRef<Path>>(img: &Image, path: P) -> Result<()> {
+    if img.color_space == ColorSpace::I444 {
+        let mut tmp_img = img.clone();
+        tmp_img.change_colorspace_rgb();
+        return save_png16(&tmp_img, path);
+    }
+
+    let channels = img.data.len();
+    let mut raw: Vec<u8> = Vec::with_capacity(img.width * img.height * channels * 2);
+    for i in 0..img.height {
+        for j in 0..img.width {
+            for k in 0..channels {
+                let v = img.data[k][i * img.strides[k] + j];
+                let v = std::cmp::min(65535, std::cmp::max(0, (v * 65535.0 + 0.5) as i32));
+                raw.push((v >> 8) as u8);
+                raw.push(v as u8);
+            }
+        }
+    }
+    let out_strm = BufWriter::new(try!(File::create(&path)));
+    let mut encoder = png::Encoder::new(out_strm, img.width as u32, img.height as u32);
+    let color_type = match channels {
+        1 => png::ColorType::Grayscale,
+        2 => png::ColorType::GrayscaleAlpha,
+        3 => png::ColorType::RGB,
+        _ => png::ColorType::RGBA,
+    };
+    encoder.set(color_type).set(png::BitDepth::Sixteen);
+    let mut writer = try!(encoder.write_header());
+    try!(writer.write_image_data(&raw));
+    Ok(())
+}
+
+// Uncompressed 8/16-bit gray, RGB and RGBA TIFF is read directly (the decoder of the
+// image crate cannot return 16-bit samples); compressed files go through that decoder.
+fn load_tiff<P: AsRef<Path>>(path: P) -> Result<Image> {
+    let mut buf = Vec::new();
+    try!(BufReader::new(try!(File::open(&path))).read_to_end(&mut buf));
+    let invalid = |what: &str| Error::UnsupportedFormat(format!("invalid TIFF: {} (path:{})",
+                                                               what, path.as_ref().display()));
+    let le = match buf.get(0..4) {
+        Some(b"II*\0") => true,
+        Some(b"MM\0*") => false,
+        _ => return Err(invalid("bad header")),
+    };
+    let read = |off: usize, size: usize| -> Option<u32> {
+        buf.get(off..off + size).map(|b| {
+            let mut v = 0u32;
+            for i in 0..size {
+                let byte = if le { b[size - 1 - i] } else { b[i] };
+                v = v << 8 | byte as u32;
+            }
+            v
+        })
+    };
+
+    // first IFD; tag -> values of SHORT/LONG fields
+    let ifd = try!(read(4, 4).ok_or_else(|| invalid("bad header"))) as usize;
+    let count = try!(read(ifd, 2).ok_or_else(|| invalid("truncated IFD"))) as usize;
+    let mut fields: Vec<(u32, Vec<u32>)> = Vec::new();
+    for e in 0..count {
+        let off = ifd + 2 + e * 12;
+        let (tag, typ, n) = match (read(off, 2), read(off + 2, 2), read(off + 4, 4)) {
+            (Some(t), Some(y), Some(n)) => (t, y, n as usize),
+            _ => return Err(invalid("truncated IFD")),
+        };
+        let size = match typ {
+            3 => 2,
+            4 => 4,
+            _ => continue,
+        };
+        let base = if n * size <= 4 {
+            off + 8
+        } else {
+            try!(read(off + 8, 4).ok_or_else(|| invalid("truncated IFD"))) as usize
+        };
+        let values: Option<Vec<u32>> = (0..n).map(|i| read(base + i * size, size)).collect();
+        fields.push((tag, try!(values.ok_or_else(|| invalid("field out of range")))));
+    }
+    let field = |tag: u32| fields.iter().find(|f| f.0 == tag).map(|f| &f.1[..]);
+    let first = |tag: u32, default: u32| field(tag).and_then(|v| v.first().cloned()).unwrap_or(default);
+
+    if first(259, 1) != 1 {
+        // compressed: the image crate handles 8-bit LZW
+        let img = try!(load_dynamic_image(&path));
+        return Ok(Image::from_dynamic_image(&img));
+    }
+    let (w, h) = (first(256, 0) as usize, first(257, 0) as usize);
+    let samples = first(277, 1) as usize;
+    let bits = first(258, 1);
+    let photometric = first(262, 1);
+    if field(258).map_or(false, |v| v.iter().any(|&b| b != bits)) || (bits != 8 && bits != 16) {
+        return Err(Error::UnsupportedFormat(format!("{}-bit TIFF", bits)));
+    }
+    match (photometric, samples) {
+        (0, 1) | (1, 1) | (2, 3) | (2, 4) => (),
+        _ => return Err(Error::UnsupportedFormat(
+            format!("TIFF with photometric interpretation {} and {} samples", photometric, samples))),
+    }
+    if first(284, 1) != 1 || first(317, 1) != 1 {
+        return Err(Error::UnsupportedFormat("planar or predicted TIFF".to_string()));
+    }
+
+    let mut raw: Vec<u8> = Vec::new();
+    let (offsets, lengths) = match (field(273), field(279)) {
+        (Some(o), Some(l)) => (o, l),
+        _ => return Err(invalid("missing strips")),
+    };
+    for (&o, &l) in offsets.iter().zip(lengths.iter()) {
+        match buf.get(o as usize..(o as usize).saturating_add(l as usize)) {
+            Some(strip) => raw.extend_from_slice(strip),
+            None => return Err(invalid("strip out of range")),
+        }
+    }
+    let bytes = (bits / 8) as usize;
+    let size = w.checked_mul(h).and_then(|n| n.checked_mul(samples * bytes));
+    match size {
+        Some(size) if size <= raw.len() => (),
+        _ => return Err(Error::DimensionError(format!("TIFF data is shorter than {}x{}", w, h))),
+    }
+
+    let color_space = if samples == 1 { ColorSpace::Gray } else { ColorSpace::RGB };
+    let mut img = Image::new(w, h, samples, color_space);
+    img.has_alpha = samples == 4;
+    img.sample_format = if bits == 16 { SampleFormat::U16 } else { SampleFormat::U8 };
+    let max = if bits == 16 { 65535.0 } else { 255.0 };
+    for y in 0..h {
+        for x in 0..w {
+            for k in 0..samples {
+                let off = ((y * w + x) * samples + k) * bytes;
+                let v = if bytes == 1 {
+                    raw[off] as u32
+                } else if le {
+                    (raw[off + 1] as u32) << 8 | raw[off] as u32
+                } else {
+                    (raw[off] as u32) << 8 | raw[off + 1] as u32
+                };
+                let v = v as f32 / max;
+                img.data[k][y * w + x] = if photometric == 0 { 1.0 - v } else { v };
+            }
+        }
+    }
+    Ok(img)
+}
+
+// Portable Float Map: "PF" (RGB) or "Pf" (gray), width, height and a scale whose
+// sign gives the byte order, followed by f32 samples stored bottom row first.
+fn load_pfm<P: AsRef<Path>>(path: P) -> Result<Image> {
+    let mut buf = Vec::new();
+    try!(BufReader::new(try!(File::open(&path))).read_to_end(&mut buf));
+    let mut tokens: Vec<String> = Vec::new();
+    let mut pos = 0;
+    while tokens.len() < 4 && pos < buf.len() {
+        while pos < buf.len() && (buf[pos] as char).is_whitespace() {
+            pos += 1;
+        }
+        let start = pos;
+        while pos < buf.len() && !(buf[pos] as char).is_whitespace() {
+            pos += 1;
+        }
+        tokens.push(String::from_utf8_lossy(&buf[start..pos]).into_owned());
+    }
+    pos += 1;
+
+    let invalid = || Error::UnsupportedFormat(format!("invalid PFM header (path:{})",
+                                                      path.as_ref().display()));
+    if tokens.len() < 4 {
+        return Err(invalid());
+    }
+    let channels = match tokens[0].as_ref() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid()),
+    };
+    let w: usize = try!(tokens[1].parse().map_err(|_| invalid()));
+    let h: usize = try!(tokens[2].parse().map_err(|_| invalid()));
+    let scale: f32 = try!(tokens[3].parse().map_err(|_| invalid()));
+    let little_endian = scale < 0.0;
+    let size = w.checked_mul(h)
+        .and_then(|n| n.checked_mul(channels * 4))
+        .and_then(|n| n.checked_add(pos));
+    match size {
+        Some(size) if size <= buf.len() => (),
+        Some(_) => return Err(Error::DimensionError(format!("PFM data is shorter than {}x{}", w, h))),
+        None => return Err(Error::DimensionError(format!("PFM size {}x{} is too large", w, h))),
+    }
+
+    let color_space = if channels == 1 { ColorSpace::Gray } else { ColorSpace::RGB };
+    let mut img = Image::new(w, h, channels, color_space);
+    img.sample_format = SampleFormat::F32;
+    for y in 0..h {
+        let row = &buf[pos + (h - 1 - y) * w * channels * 4..];
+        for x in 0..w {
+            for k in 0..channels {
+                let off = (x * channels + k) * 4;
+                let b = &row[off..off + 4];
+                let bits = if little_endian {
+                    (b[3] as u32) << 24 | (b[2] as u32) << 16 | (b[1] as u32) << 8 | b[0] as u32
+                } else {
+                    (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32
+                };
+                img.data[k][y * w + x] = f32::from_bits(bits);
+            }
+        }
+    }
+    Ok(img)
+}
+
+fn save_pfm<P: AsRef<Path>>(img: &Image, path: P) -> Result<()> {
+    if img.color_space == ColorSpace::I444 {
+        let mut tmp_img = img.clone();
+        tmp_img.change_colorspace_rgb();
+        return save_pfm(&tmp_img, path);
+    }
+
+    // PFM has no alpha channel
+    let (magic, channels) = if img.color_space == ColorSpace::Gray { ("Pf", 1) } else { ("PF", 3) };
+    let mut out_strm = BufWriter::new(try!(File::create(&path)));
+    try!(write!(out_strm, "{}\n{} {}\n-1.0\n", magic, img.width, img.height));
+    let mut row: Vec<u8> = Vec::with_capacity(img.width * channels * 4);
+    for y in (0..img.height).rev() {
+        row.clear();
+        for x in 0..img.width {
+            for k in 0..channels {
+                let v = img.data[k][y * img.strides[k] + x];
+                let bits = v.to_bits();
+                row.push(bits as u8);
+                row.push((bits >> 8) as u8);
+                row.push((bits >> 16) as u8);
+                row.push((bits >> 24) as u8);
+            }
+        }
+        try!(out_strm.write_all(&row));
+    }
+    Ok(())
+}
+
+pub fn format_from_path<P: AsRef<Path>>(path: P) -> Result<piston_image::ImageFormat> {
+    let path = path.as_ref();
+    let ext = match path.extension().and_then(|x| x.to_str()) {
+        Some(x) => x.to_lowercase(),
+        None => return Err(Error::UnsupportedFormat(
+            format!("missing file extension (path:{})", path.display()))),
+    };
+    match ext.as_ref() {
+        "jpg" | "jpeg" => Ok(piston_image::ImageFormat::JPEG),
+        "png" => Ok(piston_image::ImageFormat::PNG),
+        "gif" => Ok(piston_image::ImageFormat::GIF),
+        "webp" => Ok(piston_image::ImageFormat::WEBP),
+        "bmp" => Ok(piston_image::ImageFormat::BMP),
+        x => Err(Error::UnsupportedFormat(
+            format!("unknown file type: {} (path:{})", x, path.display()))),
+    }
+}
+
+pub fn load_dynamic_image<P: AsRef<Path>>(path: P) -> Result<piston_image::DynamicImage> {
+    let format = try!(format_from_path(&path));
+    let in_strm = try!(File::open(&path));
+    Ok(try!(piston_image::load(in_strm, format)))
+}
+
+pub fn save_dynamic_image<P: AsRef<Path>>(img: &piston_image::DynamicImage, path: P) -> Result<()> {
+    let format = try!(format_from_path(&path));
+    let mut out_strm = try!(File::create(&path));
+    if format == piston_image::ImageFormat::PNG {
+        try!(img.save(&mut out_strm, format));
+        return Ok(());
+    }
+    // the other encoders cannot store an alpha channel
+    match *img {
+        piston_image::DynamicImage::ImageLumaA8(_) => {
+            let gray = piston_image::DynamicImage::ImageLuma8(img.to_luma());
+            try!(gray.save(&mut out_strm, format));
+        },
+        piston_image::DynamicImage::ImageRgba8(_) => {
+            let rgb = piston_image::DynamicImage::ImageRgb8(img.to_rgb());
+            try!(rgb.save(&mut out_strm, format));
+        },
+        _ => try!(img.save(&mut out_strm, format)),
+    }
+    Ok(())
+}
@@ -0,0 +1,651 @@
+#![feature(step_by)]
+
+extern crate glob;
+#[cfg(feature = "memmap")]
+extern crate memmap;
+extern crate rustc_serialize;
+extern crate image as piston_image;
+extern crate png;
+extern crate time;
+
+use std::path::{Path, PathBuf};
+
+pub mod backend;
+pub mod batch;
+pub mod catalog;
+pub mod cnn;
+pub mod error;
+pub mod model;
+pub mod image;
+pub mod noise;
+
+pub use backend::{Backend, PreparedModel};
+pub use error::{Error, Result};
+
+use backend::BackendConfig;
+use catalog::{ModelCatalog, ModelKind};
+use image::{Image, Interpolation, PaddingMode};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Method {
+    Noise,
+    Scale,
+    NoiseScale,
+}
+
+/// Noise level of the noise model.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NoiseLevel {
+    Level(u32),
+    /// estimated per image from its JPEG compression (see noise::estimate_level),
+    /// rounded to the nearest level present in the model directory
+    Auto,
+}
+
+/// How the alpha plane of an image is upscaled.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AlphaMode {
+    /// run the alpha plane through the scale model (replicated into every input plane)
+    Model,
+    /// keep the interpolated alpha plane produced by Image::scale2x
+    Interpolation,
+}
+
+pub struct UpscalerBuilder {
+    model_dir: PathBuf,
+    method: Method,
+    noise_level: NoiseLevel,
+    scale: f64,
+    width: Option<usize>,
+    height: Option<usize>,
+    backend: String,
+    threads: usize,
+    tile_size: Option<usize>,
+    max_memory: Option<usize>,
+    alpha_mode: AlphaMode,
+    interpolation: Interpolation,
+    chroma_interpolation: Option<Interpolation>,
+    padding_mode: PaddingMode,
+    tta: usize,
+}
+
+impl UpscalerBuilder {
+    pub fn new<P: AsRef<Path>>(model_dir: P) -> UpscalerBuilder {
+        UpscalerBuilder {
+            model_dir: model_dir.as_ref().to_path_buf(),
+            method: Method::Scale,
+            noise_level: NoiseLevel::Level(1),
+            scale: 2.0,
+            width: None,
+            height: None,
+            backend: backend::DEFAULT_BACKEND.to_string(),
+            threads: 1,
+            tile_size: None,
+            max_memory: None,
+            alpha_mode: AlphaMode::Model,
+            interpolation: Interpolation::Nearest,
+            chroma_interpolation: None,
+            padding_mode: PaddingMode::Replicate,
+            tta: 1,
+        }
+    }
+
+    pub fn method(mut self, method: Method) -> UpscalerBuilder {
+        self.method = method;
+        self
+    }
+
+    pub fn noise_level(mut self, noise_level: NoiseLevel) -> UpscalerBuilder {
+        self.noise_level = noise_level;
+        self
+    }
+
+    /// scale factor, ignored when a target width or height is given
+    pub fn scale(mut self, scale: f64) -> UpscalerBuilder {
+        self.scale = scale;
+        self
+    }
+
+    /// output width; the height follows the aspect ratio unless set as well
+    pub fn width(mut self, width: Option<usize>) -> UpscalerBuilder {
+        self.width = width;
+        self
+    }
+
+    /// output height; the width follows the aspect ratio unless set as well
+    pub fn height(mut self, height: Option<usize>) -> UpscalerBuilder {
+        self.height = height;
+        self
+    }
+
+    /// name of a backend registered in backend::list()
+    pub fn backend(mut self, name: &str) -> UpscalerBuilder {
+        self.backend = name.to_string();
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> UpscalerBuilder {
+        self.threads = std::cmp::max(1, threads);
+        self
+    }
+
+    pub fn tile_size(mut self, tile_size: Option<usize>) -> UpscalerBuilder {
+        self.tile_size = tile_size;
+        self
+    }
+
+    /// memory budget in bytes for the CNN working buffers
+    pub fn max_memory(mut self, max_memory: Option<usize>) -> UpscalerBuilder {
+        self.max_memory = max_memory;
+        self
+    }
+
+    pub fn alpha_mode(mut self, alpha_mode: AlphaMode) -> UpscalerBuilder {
+        self.alpha_mode = alpha_mode;
+        self
+    }
+
+    /// filter used to double the image before the CNN
+    pub fn interpolation(mut self, interpolation: Interpolation) -> UpscalerBuilder {
+        self.interpolation = interpolation;
+        self
+    }
+
+    /// filter for the chroma planes a Y model does not touch
+    /// (default: same as interpolation)
+    pub fn chroma_interpolation(mut self, interpolation: Option<Interpolation>) -> UpscalerBuilder {
+        self.chroma_interpolation = interpolation;
+        self
+    }
+
+    /// how the image border is extended for the CNN
+    pub fn padding_mode(mut self, padding_mode: PaddingMode) -> UpscalerBuilder {
+        self.padding_mode = padding_mode;
+        self
+    }
+
+    /// test-time augmentation: average the model over 1, 2, 4 or 8 flipped/transposed copies
+    pub fn tta(mut self, level: usize) -> UpscalerBuilder {
+        self.tta = level;
+        self
+    }
+
+    pub fn build(self) -> Result<Upscaler> {
+        match self.tta {
+            1 | 2 | 4 | 8 => (),
+            _ => return Err(Error::InvalidArgument(
+                format!("tta level must be 1, 2, 4 or 8, got {}", self.tta))),
+        }
+        if !(self.scale > 0.0) || self.scale.is_infinite() {
+            return Err(Error::InvalidArgument(format!("invalid scale factor {}", self.scale)));
+        }
+        if self.tile_size == Some(0) {
+            return Err(Error::InvalidArgument("tile size must be positive".to_string()));
+        }
+        if self.max_memory == Some(0) {
+            return Err(Error::InvalidArgument("memory budget must be positive".to_string()));
+        }
+        let backend = match backend::create(&self.backend, &BackendConfig { threads: self.threads }) {
+            Some(b) => b,
+            None => return Err(Error::InvalidArgument(
+                format!("unknown backend \"{}\"", self.backend))),
+        };
+        // only the models the method runs on are loaded; other scale factors
+        // are reached with repeated 2x passes and a final resize
+        let catalog = ModelCatalog::new(&self.model_dir);
+        let mut scale_model = None;
+        let mut noise_models = Vec::new();
+        for kind in ModelCatalog::required(self.method, self.noise_level) {
+            let prepared = backend.prepare_packed(try!(catalog.load(kind)));
+            match kind {
+                ModelKind::Scale2x => scale_model = Some(prepared),
+                ModelKind::Noise(level) => noise_models.push((level, prepared)),
+            }
+        }
+        // auto needs every noise model as the level is picked per image
+        if self.noise_level == NoiseLevel::Auto && self.method != Method::Scale {
+            let levels = catalog.noise_levels();
+            if levels.is_empty() {
+                return Err(Error::ModelError(model::LoadModelError::NotFound {
+                    files: vec!["noise*_model.json".to_string(),
+                                "noise*_model.bin".to_string()],
+                    dir: self.model_dir.clone(),
+                }));
+            }
+            for level in levels {
+                let prepared = backend.prepare_packed(try!(catalog.load(ModelKind::Noise(level))));
+                noise_models.push((level, prepared));
+            }
+        }
+        if let (None, Some(budget)) = (self.tile_size, self.max_memory) {
+            let needed = scale_model.iter().chain(noise_models.iter().map(|m| &m.1))
+                .map(|net| min_tile_memory(&**net)).max().unwrap_or(0);
+            if needed > budget {
+                return Err(Error::InvalidArgument(format!(
+                    "memory budget of {} bytes is too small, the models need at least {} bytes",
+                    budget, needed)));
+            }
+        }
+
+        Ok(Upscaler {
+            method: self.method,
+            scale: self.scale,
+            width: self.width,
+            height: self.height,
+            noise_level: self.noise_level,
+            scale_model: scale_model,
+            noise_models: noise_models,
+            config: FilterConfig {
+                tile_size: self.tile_size,
+                max_memory: self.max_memory,
+                alpha_mode: self.alpha_mode,
+                interpolation: self.interpolation,
+                chroma_interpolation: self.chroma_interpolation.unwrap_or(self.interpolation),
+                padding_mode: self.padding_mode,
+                tta: self.tta,
+            },
+        })
+    }
+}
+
+pub struct Upscaler {
+    method: Method,
+    scale: f64,
+    width: Option<usize>,
+    height: Option<usize>,
+    noise_level: NoiseLevel,
+    // None/empty if the method does not use the model
+    scale_model: Option<Box<PreparedModel>>,
+    noise_models: Vec<(u32, Box<PreparedModel>)>,
+    config: FilterConfig,
+}
+
+pub struct Upscaled {
+    pub image: Image,
+    pub perf: PerfStatus,
+    /// noise level the image was filtered with, None for Method::Scale
+    pub noise_level: Option<u32>,
+}
+
+impl Upscaler {
+    pub fn upscale(&self, img: Image) -> Result<Upscaled> {
+        self.upscale_source(img, None)
+    }
+
+    /// Same as upscale; with NoiseLevel::Auto the level of a JPEG source file
+    /// is estimated from its quantization tables instead of the decoded image.
+    pub fn upscale_file<P: AsRef<Path>>(&self, img: Image, source: P) -> Result<Upscaled> {
+        self.upscale_source(img, Some(source.as_ref()))
+    }
+
+    fn upscale_source(&self, img: Image, source: Option<&Path>) -> Result<Upscaled> {
+        let mut perf = PerfStatus::new();
+        let (out_img, level) = try!(self.run(img, source, &mut perf));
+        Ok(Upscaled {
+            image: out_img,
+            perf: perf,
+            noise_level: level,
+        })
+    }
+
+    pub fn upscale_dynamic_image(&self, img: &piston_image::DynamicImage) -> Result<Upscaled> {
+        let mut perf = PerfStatus::new();
+        let start = time::precise_time_s();
+        let src_img = Image::from_dynamic_image(img);
+        perf.other_time += time::precise_time_s() - start;
+
+        let (out_img, level) = try!(self.run(src_img, None, &mut perf));
+        Ok(Upscaled {
+            image: out_img,
+            perf: perf,
+            noise_level: level,
+        })
+    }
+
+    fn run(&self, img: Image, source: Option<&Path>, perf: &mut PerfStatus) -> Result<(Image, Option<u32>)> {
+        if img.width == 0 || img.height == 0 {
+            return Err(Error::DimensionError(
+                format!("empty image ({}x{})", img.width, img.height)));
+        }
+        let color_planes = if img.color_space == image::ColorSpace::Gray { 1 } else { 3 };
+        let planes = color_planes + if img.has_alpha { 1 } else { 0 };
+        if img.data.len() != planes {
+            return Err(Error::DimensionError(
+                format!("expected {} planes, got {}", planes, img.data.len())));
+        }
+        let gray = img.color_space == image::ColorSpace::Gray;
+        let (width, height) = self.target_size(img.width, img.height);
+        let level = match self.method {
+            Method::Scale => None,
+            _ => Some(self.pick_noise_level(&img, source, perf)),
+        };
+        let mut output = match self.method {
+            Method::Scale => {
+                self.scale_to(img, width, height, perf)
+            },
+            Method::Noise => {
+                filter(img, self.noise_model(level), &self.config, perf)
+            },
+            Method::NoiseScale => {
+                let tmp = filter(img, self.noise_model(level), &self.config, perf);
+                self.scale_to(tmp, width, height, perf)
+            },
+        };
+        if gray {
+            output.change_colorspace(image::ColorSpace::Gray);
+        }
+        Ok((output, level))
+    }
+
+    fn pick_noise_level(&self, img: &Image, source: Option<&Path>, perf: &mut PerfStatus) -> u32 {
+        match self.noise_level {
+            NoiseLevel::Level(level) => level,
+            NoiseLevel::Auto => {
+                let start = time::precise_time_s();
+                let levels: Vec<u32> = self.noise_models.iter().map(|&(l, _)| l).collect();
+                let estimate = noise::estimate_level(source, img);
+                perf.other_time += time::precise_time_s() - start;
+                noise::nearest_level(estimate, &levels).expect("noise models are loaded for Noise/NoiseScale")
+            },
+        }
+    }
+
+    fn scale_model(&self) -> &PreparedModel {
+        &**self.scale_model.as_ref().expect("scale model is loaded for Scale/NoiseScale")
+    }
+
+    fn noise_model(&self, level: Option<u32>) -> &PreparedModel {
+        let model = self.noise_models.iter().find(|&&(l, _)| Some(l) == level);
+        &*model.expect("noise model is loaded for Noise/NoiseScale").1
+    }
+
+    /// Output size for an input of width x height.
+    pub fn target_size(&self, width: usize, height: usize) -> (usize, usize) {
+        let round = |v: f64| std::cmp::max(1, v.round() as usize);
+        match (self.width, self.height) {
+            (Some(w), Some(h)) => (w, h),
+            (Some(w), None) => (w, round(height as f64 * w as f64 / width as f64)),
+            (None, Some(h)) => (round(width as f64 * h as f64 / height as f64), h),
+            (None, None) => (round(width as f64 * self.scale), round(height as f64 * self.scale)),
+        }
+    }
+
+    // 2x passes until the target is reached or exceeded, then a Lanczos3
+    // resize down to the exact size.
+    fn scale_to(&self, img: Image, width: usize, height: usize, perf: &mut PerfStatus) -> Image {
+        let mut output = img;
+        while output.width < width || output.height < height {
+            output = scale2(output, self.scale_model(), &self.config, perf);
+        }
+        if output.width != width || output.height != height {
+            let start = time::precise_time_s();
+            output = output.resize(width, height, Interpolation::Lanczos3);
+            perf.other_time += time::precise_time_s() - start;
+        }
+        output
+    }
+}
+
+fn scale2(mut img: Image, model: &PreparedModel, config: &FilterConfig, perf: &mut PerfStatus) -> Image {
+    let start = time::precise_time_s();
+    if model.model()[0].nInputPlane == 1 && img.color_space == image::ColorSpace::RGB {
+        // convert first so the chroma planes get their own filter
+        img.change_colorspace(image::ColorSpace::I444);
+    }
+    let mut tmp = img.scale2x(config.interpolation, config.chroma_interpolation);
+    let alpha = tmp.take_alpha();
+    perf.other_time += time::precise_time_s() - start;
+
+    let mut output = filter(tmp, model, config, perf);
+    if let Some(alpha) = alpha {
+        let alpha = match config.alpha_mode {
+            AlphaMode::Model => filter_alpha(alpha, model, config, perf),
+            AlphaMode::Interpolation => alpha,
+        };
+        output.set_alpha(alpha);
+    }
+    output
+}
+
+// Runs a single-plane alpha image through the model by feeding it as every
+// input plane, and averages the output planes back into one plane.
+fn filter_alpha(alpha: Image, net: &PreparedModel, config: &FilterConfig, perf: &mut PerfStatus) -> Image {
+    let start = time::precise_time_s();
+    let num_in = net.model()[0].nInputPlane as usize;
+    let mut img = alpha.clone();
+    if num_in == 1 {
+        img.color_space = image::ColorSpace::Gray;
+    } else {
+        img.data.push(alpha.data[0].clone());
+        img.data.push(alpha.data[0].clone());
+        img.strides = vec![alpha.strides[0]; 3];
+    }
+    perf.other_time += time::precise_time_s() - start;
+
+    let output = filter(img, net, config, perf);
+
+    let start = time::precise_time_s();
+    let mut out = alpha;
+    for y in 0..out.height {
+        for x in 0..out.width {
+            let mut v = 0.0;
+            for k in 0..num_in {
+                v += output.data[k][y * output.strides[k] + x];
+            }
+            v /= num_in as f32;
+            out.data[0][y * out.strides[0] + x] = if v < 0.0 { 0.0 } else if v > 1.0 { 1.0 } else { v };
+        }
+    }
+    perf.other_time += time::precise_time_s() - start;
+    out
+}
+
+fn filter(mut img: Image, net: &PreparedModel, config: &FilterConfig, perf: &mut PerfStatus) -> Image {
+    let model = net.model();
+    let mut start = time::precise_time_s();
+    let alpha = img.take_alpha();
+    if model[0].nInputPlane == 1 {
+        // Y model: grayscale images are fed as they are
+        if img.color_space == image::ColorSpace::RGB {
+            img.change_colorspace(image::ColorSpace::I444);
+        }
+    } else if img.color_space != image::ColorSpace::RGB {
+        img.change_colorspace(image::ColorSpace::RGB);
+    }
+    perf.other_time += time::precise_time_s() - start;
+
+    let mut output = filter_cnn(&img, net, config, perf);
+    if config.tta > 1 {
+        let tta_start = time::precise_time_s();
+        for t in 1..config.tta {
+            start = time::precise_time_s();
+            let tmp = tta_transform(&img, t);
+            perf.other_time += time::precise_time_s() - start;
+
+            let out = filter_cnn(&tmp, net, config, perf);
+
+            start = time::precise_time_s();
+            let out = tta_inverse(&out, t);
+            for k in 0..output.data.len() {
+                let (stride, out_stride) = (output.strides[k], out.strides[k]);
+                for y in 0..output.height {
+                    for x in 0..output.width {
+                        output.data[k][y * stride + x] += out.data[k][y * out_stride + x];
+                    }
+                }
+            }
+            perf.other_time += time::precise_time_s() - start;
+        }
+        let n = config.tta as f32;
+        for v in output.data.iter_mut() {
+            for a in v.iter_mut() {
+                *a /= n;
+            }
+        }
+        perf.tta_time += time::precise_time_s() - tta_start;
+    }
+
+    if output.data.len() < img.data.len() {
+        // planes the model does not touch (chroma of I444)
+        start = time::precise_time_s();
+        for k in output.data.len()..img.data.len() {
+            output.data.push(img.data[k].clone());
+            output.strides.push(img.strides[k]);
+        }
+        perf.other_time += time::precise_time_s() - start;
+    }
+    if let Some(alpha) = alpha {
+        output.set_alpha(alpha);
+    }
+    output
+}
+
+// Pads the image and runs the CNN on it, tiled if the config asks for it.
+fn filter_cnn(img: &Image, net: &PreparedModel, config: &FilterConfig, perf: &mut PerfStatus) -> Image {
+    let mut start = time::precise_time_s();
+    let (pad_x, pad_y) = model::padding(net.model());
+    let padded = img.add_padding(pad_x, pad_y, config.padding_mode);
+    perf.other_time += time::precise_time_s() - start;
+
+    start = time::precise_time_s();
+    let output = match choose_tile_size(img.width, img.height, net, config) {
+        Some(tile) => filter_tiled(&padded, net, tile, perf),
+        None => net.filter(padded, perf),
+    };
+    perf.cnn_time += time::precise_time_s() - start;
+    output
+}
+
+// TTA transform t: bit 0 flips horizontally, bit 1 vertically, bit 2 transposes.
+/// Variant `t` (0..8) of the test-time augmentation: bit 2 transposes,
+/// bit 0 flips horizontally and bit 1 vertically.
+pub fn tta_transform(img: &Image, t: usize) -> Image {
+    let mut out = if t & 4 != 0 { img.transpose() } else { img.clone() };
+    if t & 1 != 0 {
+        out = out.flip_horizontal();
+    }
+    if t & 2 != 0 {
+        out = out.flip_vertical();
+    }
+    out
+}
+
+/// Undoes tta_transform(img, t).
+pub fn tta_inverse(img: &Image, t: usize) -> Image {
+    let mut out = if t & 2 != 0 { img.flip_vertical() } else { img.clone() };
+    if t & 1 != 0 {
+        out = out.flip_horizontal();
+    }
+    if t & 4 != 0 {
+        out = out.transpose();
+    }
+    out
+}
+
+// Runs the CNN on overlapping tiles of an already padded image. Every tile is
+// cut with a halo of model::padding(model) pixels, so the tiles join without seams.
+// cpu1/cpu2 produce the same result as processing the whole image at once; the GEMM
+// and Winograd kernels may differ in rounding as their blocking depends on the tile.
+fn filter_tiled(padded: &Image, net: &PreparedModel, tile: usize, perf: &mut PerfStatus) -> Image {
+    let (pad_x, pad_y) = model::padding(net.model());
+    let width = padded.width - pad_x * 2;
+    let height = padded.height - pad_y * 2;
+    let mut output: Option<Image> = None;
+
+    for y in (0..height).step_by(tile) {
+        let tile_h = std::cmp::min(tile, height - y);
+        for x in (0..width).step_by(tile) {
+            let tile_w = std::cmp::min(tile, width - x);
+            let part = padded.crop(x, y, tile_w + pad_x * 2, tile_h + pad_y * 2);
+            let out = net.filter(part, perf);
+            if output.is_none() {
+                let mut img = Image::new(width, height, out.data.len(), out.color_space.clone());
+                img.sample_format = out.sample_format;
+                output = Some(img);
+            }
+            output.as_mut().unwrap().paste(&out, x, y);
+        }
+    }
+    output.unwrap()
+}
+
+// working memory of the smallest (1x1) tile
+fn min_tile_memory(net: &PreparedModel) -> usize {
+    let (pad_x, pad_y) = model::padding(net.model());
+    net.buffer_size(1 + pad_x * 2, 1 + pad_y * 2)
+}
+
+fn choose_tile_size(width: usize, height: usize, net: &PreparedModel,
+                    config: &FilterConfig) -> Option<usize> {
+    if config.tile_size.is_some() {
+        return config.tile_size;
+    }
+    let budget = match config.max_memory {
+        Some(v) => v,
+        None => return None,
+    };
+    let (pad_x, pad_y) = model::padding(net.model());
+    let usage = |w: usize, h: usize| {
+        net.buffer_size(w + pad_x * 2, h + pad_y * 2)
+    };
+    if usage(width, height) <= budget {
+        return None;
+    }
+
+    // largest square tile that fits into the budget (build() checked that 1x1 does)
+    let (mut lo, mut hi) = (1, std::cmp::max(width, height));
+    while lo < hi {
+        let mid = (lo + hi + 1) / 2;
+        if usage(mid, mid) <= budget {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    Some(lo)
+}
+
+struct FilterConfig {
+    tile_size: Option<usize>,
+    max_memory: Option<usize>,
+    alpha_mode: AlphaMode,
+    interpolation: Interpolation,
+    chroma_interpolation: Interpolation,
+    padding_mode: PaddingMode,
+    tta: usize,
+}
+
+pub struct PerfStatus {
+    pub cnn_flo: u64,
+    pub cnn_time: f64,
+    pub other_time: f64,
+    pub thread_time: Vec<f64>,
+    /// time spent on the additional TTA passes (also included in cnn_time/other_time)
+    pub tta_time: f64,
+}
+
+impl PerfStatus {
+    pub fn new() -> PerfStatus {
+        PerfStatus {
+            cnn_flo: 0,
+            cnn_time: 0.0,
+            other_time: 0.0,
+            thread_time: Vec::new(),
+            tta_time: 0.0,
+        }
+    }
+
+    /// Adds the counters of another run, e.g. to total a batch.
+    pub fn merge(&mut self, other: &PerfStatus) {
+        self.cnn_flo += other.cnn_flo;
+        self.cnn_time += other.cnn_time;
+        self.other_time += other.other_time;
+        self.tta_time += other.tta_time;
+        if self.thread_time.len() < other.thread_time.len() {
+            self.thread_time.resize(other.thread_time.len(), 0.0);
+        }
+        for (a, b) in self.thread_time.iter_mut().zip(other.thread_time.iter()) {
+            *a += *b;
+        }
+    }
+}
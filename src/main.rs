@@ -1,36 +1,68 @@
-#![feature(step_by)]
-
 extern crate getopts;
-extern crate rustc_serialize;
-extern crate image as piston_image;
 extern crate time;
+extern crate waifu2x_hsa;
 
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::path::Path;
-use std::fs::File;
 
-use getopts::Options;
+use getopts::{Options, Matches};
 
-mod cnn;
-mod model;
-mod image;
+use waifu2x_hsa::{UpscalerBuilder, Upscaler, Upscaled, Method, NoiseLevel, AlphaMode, PerfStatus, Error, Result};
+use waifu2x_hsa::{backend, batch, catalog, cnn, image, model};
+use waifu2x_hsa::image::{Interpolation, PaddingMode};
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
+    if args.len() > 1 && args[1] == "model" {
+        if let Err(e) = model_command(&args[0], &args[2..]) {
+            let _ = writeln!(std::io::stderr(), "error: {}", e);
+            std::process::exit(exit_code(&e));
+        }
+        return;
+    }
+
     let mut opts = Options::new();
-    opts.reqopt("i", "input", "input image path (required)", "INPUT");
-    opts.reqopt("o", "output", "output image path (required)", "OUTPUT");
-    opts.optopt("s", "scale", "scale factor (default: 2)", "SCALE");
+    opts.optmulti("i", "input", "input image, directory or glob pattern (repeatable)", "INPUT");
+    opts.optopt("o", "output", "output image path, or output directory for batches (required)", "OUTPUT");
+    opts.optflag("r", "recursive", "scan input directories recursively");
+    opts.optopt("j", "jobs", "number of decode and of encode threads in batch mode (default: 1)", "JOBS");
+    opts.optopt("", "name-template",
+                &format!("output file name in batch mode, {{stem}}, {{ext}} and {{name}} are replaced \
+                          (default: {})", batch::DEFAULT_TEMPLATE), "TEMPLATE");
+    opts.optopt("s", "scale", "scale factor, e.g. 2, 4 or 1.6 (default: 2)", "SCALE");
+    opts.optopt("", "width", "output width (keeps the aspect ratio unless --height is given)", "WIDTH");
+    opts.optopt("", "height", "output height (keeps the aspect ratio unless --width is given)", "HEIGHT");
     opts.optopt("m", "method", "noise|scale|noise_scale (default: scale)", "METHOD");
-    opts.reqopt("d", "model_dir", "model directory (required)", "DIR");
-    opts.optopt("n", "noise_level", "1 or 2 (default: 1)", "LEVEL");
+    opts.optopt("d", "model_dir", "model directory, or the directory to look for --style in", "DIR");
+    opts.optopt("", "style",
+                &format!("model style, e.g. anime_style_art or photo; the _rgb variant is preferred \
+                          (default: {})", catalog::DEFAULT_STYLE), "STYLE");
+    opts.optflag("", "list-models",
+                 &format!("list the model styles found in the search path (${} and the XDG data dirs)",
+                          catalog::MODEL_PATH_ENV));
+    opts.optopt("n", "noise-level",
+                "level of a noise{LEVEL}_model in the model directory (e.g. 0-3), or auto to \
+                 estimate it per image from the JPEG compression (default: 1)", "LEVEL");
+    opts.optopt("t", "threads", "number of worker threads (default: 1)", "THREADS");
+    opts.optopt("", "tile-size", "process the image in tiles of SIZE x SIZE pixels", "SIZE");
+    opts.optopt("", "max-memory", "choose the tile size so the CNN buffer fits in MB megabytes", "MB");
+    opts.optopt("", "alpha", "model|interpolation: how to upscale the alpha channel (default: model)", "MODE");
+    opts.optopt("", "interpolation",
+                "nearest|bilinear|bicubic|lanczos3: filter used before the CNN (default: nearest)", "FILTER");
+    opts.optopt("", "chroma-interpolation",
+                "filter for the chroma planes with Y models (default: same as --interpolation)", "FILTER");
+    opts.optopt("", "padding-mode",
+                "replicate|reflect|mirror-101|zero|wrap: how the border is extended (default: replicate)", "MODE");
+    opts.optopt("", "tta", "1|2|4|8: average over flipped/transposed copies (default: 1)", "LEVEL");
+    opts.optopt("b", "backend", &backend_help(), "BACKEND");
     opts.optflag("h", "help", "print this help menu");
-    let matches = match opts.parse(&args[1..]) {
+    let matches = match opts.parse(&hidden_aliases(&args[1..])) {
         Ok(m) => m,
         Err(f) => {
             println!("{}", f.to_string());
             print_usage(&args[0], opts);
-            return;
+            std::process::exit(exit_code(&Error::InvalidArgument(f.to_string())));
         }
     };
     if matches.opt_present("h") {
@@ -38,129 +70,379 @@ fn main() {
         return;
     }
 
-    let in_path = matches.opt_str("i").unwrap();
-    let out_path = matches.opt_str("o").unwrap();
-    let model_dir = matches.opt_str("d").unwrap();
+    let result = if matches.opt_present("list-models") {
+        list_models(&matches)
+    } else {
+        run(&matches)
+    };
+    if let Err(e) = result {
+        let _ = writeln!(std::io::stderr(), "error: {}", e);
+        std::process::exit(exit_code(&e));
+    }
+}
+
+fn run(matches: &Matches) -> Result<()> {
+    let mut inputs = matches.opt_strs("i");
+    inputs.extend(matches.free.iter().cloned());
+    if inputs.is_empty() {
+        return Err(Error::InvalidArgument("no input given (-i)".to_string()));
+    }
+    let out_path = match matches.opt_str("o") {
+        Some(x) => x,
+        None => return Err(Error::InvalidArgument("no output given (-o)".to_string())),
+    };
     let scale = match matches.opt_str("s") {
-        Some(x) => match u32::from_str(x.as_ref()) {
-            Ok(v) => v,
-            Err(_) => panic!("cannot parse {} to unsigned-integer", x),
+        Some(x) => match f64::from_str(x.as_ref()) {
+            Ok(v) if v > 0.0 && !v.is_infinite() => v,
+            _ => return Err(Error::InvalidArgument(
+                format!("cannot parse {} to positive number", x))),
         },
-        None => 2
+        None => 2.0
     };
     let method = match matches.opt_str("m") {
-        Some(x) => x,
-        None => "scale".to_string()
+        Some(x) => match x.as_ref() {
+            "scale" => Method::Scale,
+            "noise" => Method::Noise,
+            "noise_scale" => Method::NoiseScale,
+            _ => return Err(Error::InvalidArgument(format!("unknown method \"{}\"", x))),
+        },
+        None => Method::Scale
     };
     let noise_level = match matches.opt_str("n") {
+        Some(ref x) if x == "auto" => NoiseLevel::Auto,
+        Some(x) => match u32::from_str(&x) {
+            Ok(level) => NoiseLevel::Level(level),
+            Err(_) => return Err(Error::InvalidArgument(format!("unknown noise-level {}", x))),
+        },
+        None => NoiseLevel::Level(1)
+    };
+    let alpha_mode = match matches.opt_str("alpha") {
         Some(x) => match x.as_ref() {
-            "1" | "2" => x,
-            _ => panic!("unknown noise-level {}", x),
+            "model" => AlphaMode::Model,
+            "interpolation" => AlphaMode::Interpolation,
+            _ => return Err(Error::InvalidArgument(format!("unknown alpha mode \"{}\"", x))),
         },
-        None => "1".to_string()
+        None => AlphaMode::Model
     };
+    let width = try!(parse_positive(matches, "width"));
+    let height = try!(parse_positive(matches, "height"));
+    let interpolation = try!(parse_interpolation(matches, "interpolation"))
+        .unwrap_or(Interpolation::Nearest);
+    let chroma_interpolation = try!(parse_interpolation(matches, "chroma-interpolation"));
+    let padding_mode = match matches.opt_str("padding-mode") {
+        Some(x) => match x.as_ref() {
+            "replicate" => PaddingMode::Replicate,
+            "reflect" => PaddingMode::Reflect,
+            "mirror-101" => PaddingMode::Mirror101,
+            "zero" => PaddingMode::Zero,
+            "wrap" => PaddingMode::Wrap,
+            _ => return Err(Error::InvalidArgument(format!("unknown padding mode \"{}\"", x))),
+        },
+        None => PaddingMode::Replicate
+    };
+    let tta = try!(parse_positive(matches, "tta")).unwrap_or(1);
+    let backend_name = matches.opt_str("b").unwrap_or(backend::DEFAULT_BACKEND.to_string());
+    let threads = try!(parse_positive(matches, "t")).unwrap_or(1);
+    let tile_size = try!(parse_positive(matches, "tile-size"));
+    let max_memory = try!(parse_positive(matches, "max-memory")).map(|v| v * 1024 * 1024);
 
-    let img = match File::open(&in_path) {
-        Ok(in_strm) => {
-            piston_image::load(in_strm, path_to_image_format(&in_path)).unwrap()
+    // -d alone names the model directory, with --style it is searched instead of the search path
+    let model_dir = match (matches.opt_str("d"), matches.opt_str("style")) {
+        (Some(dir), None) => PathBuf::from(dir),
+        (dir, style) => {
+            let roots = dir.map_or_else(catalog::search_path, |d| vec![PathBuf::from(d)]);
+            let style = style.unwrap_or(catalog::DEFAULT_STYLE.to_string());
+            try!(catalog::find_style(&roots, &style, method, noise_level)).dir().to_path_buf()
         },
-        _ => panic!("open error"),
     };
-    let out_img_format = path_to_image_format(&out_path);
 
-    let scale_model_path = Path::new(&model_dir).join(format!("scale{}.0x_model.json", scale));
-    let noise_model_path = Path::new(&model_dir).join(format!("noise{}_model.json", noise_level));
+    let upscaler = try!(UpscalerBuilder::new(&model_dir)
+        .method(method)
+        .noise_level(noise_level)
+        .scale(scale)
+        .width(width)
+        .height(height)
+        .backend(&backend_name)
+        .threads(threads)
+        .tile_size(tile_size)
+        .max_memory(max_memory)
+        .alpha_mode(alpha_mode)
+        .interpolation(interpolation)
+        .chroma_interpolation(chroma_interpolation)
+        .padding_mode(padding_mode)
+        .tta(tta)
+        .build());
 
-    let scale_model = Box::new(model::load_model(&scale_model_path).unwrap());
-    let noise_model = Box::new(model::load_model(&noise_model_path).unwrap());
+    let auto_noise = noise_level == NoiseLevel::Auto && method != Method::Scale;
+    let single = inputs.len() == 1 && Path::new(&inputs[0]).is_file() && !Path::new(&out_path).is_dir();
+    if single {
+        let (result, total_time) = try!(process(&upscaler, Path::new(&inputs[0]), Path::new(&out_path)));
+        if let (true, Some(level)) = (auto_noise, result.noise_level) {
+            println!("noise level: {} (auto)", level);
+        }
+        print_perf(&result.perf, total_time, tta);
+        return Ok(());
+    }
 
-    let mut perf = PerfStatus {
-        cnn_flo: 0,
-        cnn_time: 0.0,
-        other_time: 0.0,
-    };
+    let items = try!(batch::collect_inputs(&inputs, matches.opt_present("r")));
+    if items.is_empty() {
+        return Err(Error::InvalidArgument("no input images found".to_string()));
+    }
+    let template = matches.opt_str("name-template").unwrap_or(batch::DEFAULT_TEMPLATE.to_string());
+    let jobs = try!(batch::plan_jobs(items, &out_path, &template));
+    try!(std::fs::create_dir_all(&out_path));
+    let io_threads = try!(parse_positive(matches, "j")).unwrap_or(1);
 
+    // keep going after a failed file, report the first error at the end
+    let mut total = PerfStatus::new();
+    let mut total_time = 0.0;
+    let mut first_error = None;
+    let mut failed = 0;
     let start = time::precise_time_s();
-    let src_img = image::Image::from_dynamic_image(&img);
-    perf.other_time += time::precise_time_s() - start;
-
-    let out_img = match method.as_ref() {
-        "scale" => {
-            scale2(src_img, &scale_model, &mut perf)
-        },
-        "noise" => {
-            filter(src_img, &noise_model, &mut perf)
-        },
-        "noise_scale" => {
-            let tmp = filter(src_img, &noise_model, &mut perf);
-            scale2(tmp, &scale_model, &mut perf)
-        },
-        _ => {
-            panic!("unknown method \"{}\"", method);
+    batch::run_pipeline(&upscaler, &jobs, io_threads, |r| {
+        let job = &jobs[r.index];
+        match r.result {
+            Ok(perf) => {
+                let t = r.decode_time + r.upscale_time + r.encode_time;
+                let level = match (auto_noise, r.noise_level) {
+                    (true, Some(l)) => format!(", noise level: {}", l),
+                    _ => String::new(),
+                };
+                println!("{} -> {}: {:.2} [ms] (decode: {:.2} [ms], upscale: {:.2} [ms], encode: {:.2} [ms]{})",
+                         job.item.input.display(), job.output.display(), t * 1000.0,
+                         r.decode_time * 1000.0, r.upscale_time * 1000.0, r.encode_time * 1000.0, level);
+                total.merge(&perf);
+                total_time += t;
+            },
+            Err(e) => {
+                let _ = writeln!(std::io::stderr(), "error: {}: {}", job.item.input.display(), e);
+                failed += 1;
+                if first_error.is_none() {
+                    first_error = Some(e);
+                }
+            },
         }
-    };
+    });
+    let elapsed = time::precise_time_s() - start;
 
-    let total_time = time::precise_time_s() - start;
+    println!("files: {} ({} failed), {:.2} [ms] ({:.2} [ms/file])", jobs.len(), failed,
+             elapsed * 1000.0, elapsed * 1000.0 / jobs.len() as f64);
+    print_perf(&total, total_time, tta);
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
 
-    let mut out_strm = File::create(&out_path).unwrap();
-    out_img.to_dynamic_image().save(&mut out_strm, out_img_format).unwrap();
+// Upscales a single file, returns the result (without the image) and the time including load and save.
+fn process(upscaler: &Upscaler, src: &Path, dst: &Path) -> Result<(Upscaled, f64)> {
+    try!(image::check_path(dst));
+    if let Some(dir) = dst.parent() {
+        try!(std::fs::create_dir_all(dir));
+    }
+    let start = time::precise_time_s();
+    let img = try!(image::load(src));
+    let result = try!(upscaler.upscale_file(img, src));
+    try!(image::save(&result.image, dst));
+    Ok((result, time::precise_time_s() - start))
+}
 
+fn print_perf(perf: &PerfStatus, total_time: f64, tta: usize) {
     println!("total: {:.2} [ms]", total_time * 1000.0);
     println!("cnn: {:.2} [GFLOPS], {:.2} [ms] ({:.2} G fp-ops)",
              (perf.cnn_flo as f64) / 1000000000.0 / perf.cnn_time,
              perf.cnn_time * 1000.0, perf.cnn_flo as f64 / 1000000000.0);
     println!("other: {:.2} [ms]", perf.other_time * 1000.0);
+    if tta > 1 {
+        println!("tta: {} passes, {:.2} [ms] extra", tta, perf.tta_time * 1000.0);
+    }
+    for (i, t) in perf.thread_time.iter().enumerate() {
+        println!("  thread{}: {:.2} [ms]", i, t * 1000.0);
+    }
 }
 
-fn scale2(img: image::Image, model: &model::Model, perf: &mut PerfStatus) -> image::Image {
-    let start = time::precise_time_s();
-    let tmp = img.scale2x();
-    perf.other_time += time::precise_time_s() - start;
+fn list_models(matches: &Matches) -> Result<()> {
+    let roots = matches.opt_str("d").map_or_else(catalog::search_path, |d| vec![PathBuf::from(d)]);
+    for root in roots.iter() {
+        let styles = catalog::styles(&[root.clone()]);
+        if styles.is_empty() {
+            continue;
+        }
+        println!("{}", root.display());
+        for &(ref name, ref c) in styles.iter() {
+            let input = match c.input_planes() {
+                Some(1) => "Y",
+                Some(3) => "RGB",
+                _ => "?",
+            };
+            let levels: Vec<String> = c.noise_levels().iter().map(|l| l.to_string()).collect();
+            let scales: Vec<String> = c.scales().iter().map(|s| format!("{}x", s)).collect();
+            println!("  {:<24} {:<4} noise levels: {:<8} scales: {}", name, input,
+                     if levels.is_empty() { "-".to_string() } else { levels.join(",") },
+                     if scales.is_empty() { "-".to_string() } else { scales.join(",") });
+        }
+    }
+    Ok(())
+}
 
-    filter(tmp, &model, perf)
+// `model <subcommand> ...`
+fn model_command(program: &str, args: &[String]) -> Result<()> {
+    let usage = format!("Usage: {0} model convert INPUT OUTPUT.bin\n       {0} model info PATH", program);
+    match args.get(0).map(|x| x.as_ref()) {
+        Some("info") if args.len() == 2 => {
+            let path = Path::new(&args[1]);
+            if !path.is_dir() {
+                return model_info(path);
+            }
+            let mut files = Vec::new();
+            for entry in try!(std::fs::read_dir(path)) {
+                let file = try!(entry).path();
+                let ext = file.extension().and_then(|x| x.to_str()).unwrap_or("").to_string();
+                if ext == "json" || ext == "bin" {
+                    files.push(file);
+                }
+            }
+            files.sort();
+            for file in files.iter() {
+                if let Err(e) = model_info(file) {
+                    println!("{}\n  error: {}", file.display(), e);
+                }
+                println!("");
+            }
+            Ok(())
+        },
+        Some("convert") if args.len() == 3 => {
+            let packed = try!(model::load_packed_model(&args[1]));
+            try!(model::save_binary_model(&packed, &args[2]));
+            println!("{} -> {} ({} layers)", args[1], args[2], packed.model.len());
+            Ok(())
+        },
+        _ => Err(Error::InvalidArgument(usage)),
+    }
+}
+
+fn model_info(path: &Path) -> Result<()> {
+    let m = try!(model::load_model_unchecked(path));
+    let valid = model::validate_model(&m);
+    println!("{}", path.display());
+    match m.first().map(|l| l.nInputPlane) {
+        Some(1) => println!("  input: Y (1 plane)"),
+        Some(3) => println!("  input: RGB (3 planes)"),
+        Some(n) => println!("  input: {} planes", n),
+        None => println!("  input: -"),
+    }
+
+    println!("  {:>5} {:>5} {:>5} {:>7} {:>10} {:>10}", "layer", "in", "out", "kernel", "params", "FLOPs/px");
+    let (mut params, mut flops) = (0u64, 0u64);
+    let mut field = (1, 1);
+    let mut non_finite = 0;
+    for (i, layer) in m.iter().enumerate() {
+        // the plane counts of a broken model may overflow u32
+        let p = [layer.nOutputPlane, layer.kW, layer.kH].iter()
+            .fold(layer.nInputPlane as u64, |acc, &v| acc.saturating_mul(v as u64))
+            .saturating_add(layer.nOutputPlane as u64);
+        let f = cnn::layer_flops(layer, 1, 1);
+        println!("  {:>5} {:>5} {:>5} {:>7} {:>10} {:>10}", i, layer.nInputPlane, layer.nOutputPlane,
+                 format!("{}x{}", layer.kW, layer.kH), p, f);
+        params = params.saturating_add(p);
+        flops = flops.saturating_add(f);
+        field.0 += (layer.kW as usize).saturating_sub(1);
+        field.1 += (layer.kH as usize).saturating_sub(1);
+        non_finite += layer.bias.iter().filter(|v| !v.is_finite()).count();
+        for w_o in layer.weight.iter() {
+            for w_oi in w_o.iter() {
+                for row in w_oi.iter() {
+                    non_finite += row.iter().filter(|v| !v.is_finite()).count();
+                }
+            }
+        }
+    }
+    println!("  parameters: {}", params);
+    println!("  receptive field: {}x{}, padding: {}x{}", field.0, field.1, field.0 / 2, field.1 / 2);
+    println!("  FLOPs per output pixel: {}", flops);
+
+    let mut warnings = Vec::new();
+    if let Err(e) = valid {
+        warnings.push(format!("{}", e));
+    }
+    if non_finite > 0 {
+        warnings.push(format!("{} weights or biases are NaN or infinite", non_finite));
+    }
+    for (i, layer) in m.iter().enumerate() {
+        if (layer.kW, layer.kH) != (3, 3) && (layer.kW, layer.kH) != (1, 1) {
+            warnings.push(format!("layer {}: {}x{} kernels run on the slower generic path",
+                                  i, layer.kW, layer.kH));
+        }
+    }
+    if warnings.is_empty() {
+        println!("  warnings: none");
+    }
+    for w in warnings.iter() {
+        println!("  warning: {}", w);
+    }
+    Ok(())
 }
 
-fn filter(mut img: image::Image, model: &model::Model, perf: &mut PerfStatus) -> image::Image {
-    let mut start = time::precise_time_s();
-    if model[0].nInputPlane == 1 && img.color_space != image::ColorSpace::I444 {
-        img.change_colorspace(image::ColorSpace::I444);
+/// Rewrites the older spellings of renamed options, which are accepted but
+/// not listed in the usage.
+fn hidden_aliases(args: &[String]) -> Vec<String> {
+    let mut out = Vec::with_capacity(args.len());
+    let mut options = true;
+    for arg in args.iter() {
+        if options && arg == "--" {
+            options = false;
+        }
+        if options && (arg == "--noise_level" || arg.starts_with("--noise_level=")) {
+            out.push(arg.replacen("--noise_level", "--noise-level", 1));
+        } else {
+            out.push(arg.clone());
+        }
     }
-    let padded = img.add_padding(model.len());
-    perf.other_time += time::precise_time_s() - start;
+    out
+}
 
-    start = time::precise_time_s();
-    let mut output = cnn::filter_cpu2(padded, &model, perf);
-    perf.cnn_time += time::precise_time_s() - start;
+fn parse_positive(matches: &Matches, name: &str) -> Result<Option<usize>> {
+    match matches.opt_str(name) {
+        Some(x) => match usize::from_str(x.as_ref()) {
+            Ok(v) if v > 0 => Ok(Some(v)),
+            _ => Err(Error::InvalidArgument(
+                format!("cannot parse {} to positive-integer (--{})", x, name))),
+        },
+        None => Ok(None)
+    }
+}
 
-    if output.data.len() == 1 {
-        start = time::precise_time_s();
-        output.data.push(img.data[1].clone());
-        output.data.push(img.data[2].clone());
-        output.strides.push(img.strides[1]);
-        output.strides.push(img.strides[2]);
-        perf.other_time += time::precise_time_s() - start;
+fn parse_interpolation(matches: &Matches, name: &str) -> Result<Option<Interpolation>> {
+    match matches.opt_str(name) {
+        Some(x) => match x.as_ref() {
+            "nearest" => Ok(Some(Interpolation::Nearest)),
+            "bilinear" => Ok(Some(Interpolation::Bilinear)),
+            "bicubic" => Ok(Some(Interpolation::Bicubic)),
+            "lanczos3" => Ok(Some(Interpolation::Lanczos3)),
+            _ => Err(Error::InvalidArgument(format!("unknown interpolation \"{}\" (--{})", x, name))),
+        },
+        None => Ok(None)
     }
-    output
 }
 
-fn path_to_image_format(path: &String) -> piston_image::ImageFormat {
-    match Path::new(&path).extension().unwrap().to_str().unwrap().to_lowercase().as_ref() {
-        "jpg" | "jpeg" => piston_image::ImageFormat::JPEG,
-        "png" => piston_image::ImageFormat::PNG,
-        "gif" => piston_image::ImageFormat::GIF,
-        "webp" => piston_image::ImageFormat::WEBP,
-        "bmp" => piston_image::ImageFormat::BMP,
-        x => panic!("unknown file type: {} (path:{})", x, &path),
+fn exit_code(e: &Error) -> i32 {
+    match *e {
+        Error::InvalidArgument(_) => 1,
+        Error::IOError(_) => 2,
+        Error::ImageError(_) => 3,
+        Error::ModelError(_) => 4,
+        Error::UnsupportedFormat(_) => 5,
+        Error::DimensionError(_) => 6,
     }
 }
 
+fn backend_help() -> String {
+    let names: Vec<String> = backend::list().iter()
+        .map(|&(name, desc)| format!("{} ({})", name, desc)).collect();
+    format!("{} (default: {})", names.join(", "), backend::DEFAULT_BACKEND)
+}
+
 fn print_usage(program: &str, opts: Options) {
     let brief = format!("Usage: {} [options]", program);
     print!("{}", opts.usage(&brief));
 }
-
-pub struct PerfStatus {
-    pub cnn_flo: u64,
-    pub cnn_time: f64,
-    pub other_time: f64,
-}
@@ -1,30 +1,156 @@
+use std;
 use std::convert::AsRef;
-use std::path::Path;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::fs::File;
-use std::io::{Error,Read};
+use std::io::{BufWriter, Error, Read, Write};
+use std::sync::Arc;
 
 use rustc_serialize::json;
 
 #[allow(non_snake_case)]
-#[derive(RustcDecodable, Debug)]
+#[derive(RustcDecodable, Clone, Debug)]
 pub struct Layer {
     pub nInputPlane: u32,
     pub nOutputPlane: u32,
     pub kW: u32,
     pub kH: u32,
     pub bias: Vec<f32>,
+    /// weight[out][in][y][x]; empty for layers loaded from a binary model,
+    /// see PackedModel::into_model.
     pub weight: Vec<Vec<Vec<Vec<f32>>>>,
 }
 
 pub type Model = Vec<Layer>;
 
+/// Weights of one layer in the layout cnn::filter_cpu2 runs on: for every input
+/// plane, nOutputPlane x kH x kW contiguous values.
+pub type LayerWeights = Arc<Vec<Vec<f32>>>;
+
+/// A model together with its packed weights, so backends do not re-pack them per run.
+#[derive(Clone)]
+pub struct PackedModel {
+    pub model: Model,
+    pub weights: Vec<LayerWeights>,
+}
+
+impl PackedModel {
+    pub fn new(model: Model) -> PackedModel {
+        let weights = model.iter().map(|l| Arc::new(pack_weights(l))).collect();
+        PackedModel {
+            model: model,
+            weights: weights,
+        }
+    }
+
+    /// The model with the nested Layer::weight filled in from the packed weights,
+    /// for the code which indexes it (cpu1, the gemm and winograd packing, `model info`).
+    pub fn into_model(self) -> Model {
+        let mut model = self.model;
+        for (layer, w) in model.iter_mut().zip(self.weights.iter()) {
+            if layer.weight.is_empty() {
+                layer.weight = unpack_weights(layer, w);
+            }
+        }
+        model
+    }
+}
+
 #[derive(Debug)]
 pub enum LoadModelError {
     IOError(Error),
     DecoderError(json::DecoderError),
+    NotFound { files: Vec<String>, dir: PathBuf },
+    StyleNotFound { style: String, files: Vec<String>, searched: Vec<PathBuf> },
+    EmptyModel,
+    InvalidInputPlanes(u32),
+    InvalidOutputPlanes { expected: u32, found: u32 },
+    UnsupportedKernelSize { layer: usize, kw: u32, kh: u32 },
+    PlaneMismatch { layer: usize, expected: u32, found: u32 },
+    InvalidBiasLength { layer: usize, expected: usize, found: usize },
+    InvalidWeightShape { layer: usize, index: String, expected: usize, found: usize },
+    InvalidBinary(String),
+}
+
+impl fmt::Display for LoadModelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadModelError::IOError(ref e) => write!(f, "{}", e),
+            LoadModelError::DecoderError(ref e) => write!(f, "{}", e),
+            LoadModelError::NotFound { ref files, ref dir } =>
+                write!(f, "{} not found in {}", files.join(" or "), dir.display()),
+            LoadModelError::StyleNotFound { ref style, ref files, ref searched } => {
+                let dirs: Vec<String> = searched.iter().map(|d| d.display().to_string()).collect();
+                write!(f, "no \"{}\" model directory with {} found (searched: {})",
+                       style, files.join(", "), dirs.join(", "))
+            },
+            LoadModelError::EmptyModel => write!(f, "model has no layers"),
+            LoadModelError::InvalidInputPlanes(n) =>
+                write!(f, "first layer takes {} input planes (expected 1 (Y) or 3 (RGB))", n),
+            LoadModelError::InvalidOutputPlanes { expected, found } =>
+                write!(f, "last layer has {} output planes (expected {})", found, expected),
+            LoadModelError::UnsupportedKernelSize { layer, kw, kh } =>
+                write!(f, "layer {}: unsupported kernel size {}x{} (must be odd)", layer, kw, kh),
+            LoadModelError::PlaneMismatch { layer, expected, found } =>
+                write!(f, "layer {}: nInputPlane is {} but previous layer outputs {} planes",
+                       layer, found, expected),
+            LoadModelError::InvalidBiasLength { layer, expected, found } =>
+                write!(f, "layer {}: bias has {} elements (expected {})", layer, found, expected),
+            LoadModelError::InvalidWeightShape { layer, ref index, expected, found } =>
+                write!(f, "layer {}: {} has {} elements (expected {})", layer, index, found, expected),
+            LoadModelError::InvalidBinary(ref s) => write!(f, "invalid binary model: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for LoadModelError {
+    fn description(&self) -> &str {
+        match *self {
+            LoadModelError::IOError(_) => "cannot read model file",
+            LoadModelError::DecoderError(_) => "cannot decode model file",
+            LoadModelError::NotFound { .. } => "model file not found",
+            LoadModelError::StyleNotFound { .. } => "model style not found",
+            LoadModelError::EmptyModel => "model has no layers",
+            LoadModelError::InvalidInputPlanes(_) => "invalid number of input planes",
+            LoadModelError::InvalidOutputPlanes { .. } => "invalid number of output planes",
+            LoadModelError::UnsupportedKernelSize { .. } => "unsupported kernel size",
+            LoadModelError::PlaneMismatch { .. } => "layer planes do not chain",
+            LoadModelError::InvalidBiasLength { .. } => "invalid bias length",
+            LoadModelError::InvalidWeightShape { .. } => "invalid weight shape",
+            LoadModelError::InvalidBinary(_) => "invalid binary model",
+        }
+    }
+}
+
+/// Loads a binary model (`.bin`, see load_binary_model) or a waifu2x JSON model.
+pub fn load_packed_model<P: AsRef<Path>>(path: P) -> Result<PackedModel, LoadModelError> {
+    if is_binary_path(&path) {
+        load_binary_model(path)
+    } else {
+        load_model(path).map(PackedModel::new)
+    }
+}
+
+/// Loads a binary or JSON model without validate_model, for inspecting broken models.
+pub fn load_model_unchecked<P: AsRef<Path>>(path: P) -> Result<Model, LoadModelError> {
+    if is_binary_path(&path) {
+        with_model_bytes(path, parse_binary_model).map(|m| m.into_model())
+    } else {
+        decode_model(path)
+    }
+}
+
+fn is_binary_path<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().extension().map_or(false, |x| x == "bin")
 }
 
 pub fn load_model<P: AsRef<Path>>(path: P) -> Result<Model, LoadModelError> {
+    let model = try!(decode_model(path));
+    try!(validate_model(&model));
+    Ok(model)
+}
+
+fn decode_model<P: AsRef<Path>>(path: P) -> Result<Model, LoadModelError> {
     let mut f = match File::open(path) {
         Ok(f) => f,
         Err(e) => return Err(LoadModelError::IOError(e)),
@@ -39,3 +165,269 @@ pub fn load_model<P: AsRef<Path>>(path: P) -> Result<Model, LoadModelError> {
         Err(e) => Err(LoadModelError::DecoderError(e)),
     }
 }
+
+/// Checks that every layer's tensors match its declared shape and that
+/// the layers chain, so the CNN implementations can index without bounds checks.
+pub fn validate_model(model: &Model) -> Result<(), LoadModelError> {
+    try!(validate_layers(model));
+    for (i, layer) in model.iter().enumerate() {
+        try!(check_len(i, || "weight".to_string(), layer.nOutputPlane, layer.weight.len()));
+        for (o, w_o) in layer.weight.iter().enumerate() {
+            try!(check_len(i, || format!("weight[{}]", o), layer.nInputPlane, w_o.len()));
+            for (j, w_oj) in w_o.iter().enumerate() {
+                try!(check_len(i, || format!("weight[{}][{}]", o, j), layer.kH, w_oj.len()));
+                for (y, row) in w_oj.iter().enumerate() {
+                    try!(check_len(i, || format!("weight[{}][{}][{}]", o, j, y), layer.kW, row.len()));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// validate_model without the nested weights (which binary models do not fill in).
+pub fn validate_layers(model: &Model) -> Result<(), LoadModelError> {
+    if model.len() == 0 {
+        return Err(LoadModelError::EmptyModel);
+    }
+    let num_in = model[0].nInputPlane;
+    if num_in != 1 && num_in != 3 {
+        return Err(LoadModelError::InvalidInputPlanes(num_in));
+    }
+    let num_out = model[model.len() - 1].nOutputPlane;
+    if num_out != num_in {
+        return Err(LoadModelError::InvalidOutputPlanes { expected: num_in, found: num_out });
+    }
+
+    for (i, layer) in model.iter().enumerate() {
+        if layer.kW % 2 == 0 || layer.kH % 2 == 0 {
+            return Err(LoadModelError::UnsupportedKernelSize { layer: i, kw: layer.kW, kh: layer.kH });
+        }
+        if i > 0 && model[i - 1].nOutputPlane != layer.nInputPlane {
+            return Err(LoadModelError::PlaneMismatch {
+                layer: i, expected: model[i - 1].nOutputPlane, found: layer.nInputPlane });
+        }
+        if layer.bias.len() != layer.nOutputPlane as usize {
+            return Err(LoadModelError::InvalidBiasLength {
+                layer: i, expected: layer.nOutputPlane as usize, found: layer.bias.len() });
+        }
+    }
+    Ok(())
+}
+
+/// Number of pixels (horizontal, vertical) the CNN shrinks each side of its input by,
+/// i.e. the padding required to keep the output the same size as the input.
+pub fn padding(model: &Model) -> (usize, usize) {
+    let mut pad_x = 0;
+    let mut pad_y = 0;
+    for layer in model.iter() {
+        pad_x += (layer.kW as usize - 1) / 2;
+        pad_y += (layer.kH as usize - 1) / 2;
+    }
+    (pad_x, pad_y)
+}
+
+fn check_len<F: Fn() -> String>(layer: usize, index: F, expected: u32, found: usize)
+                                -> Result<(), LoadModelError> {
+    if expected as usize != found {
+        return Err(LoadModelError::InvalidWeightShape {
+            layer: layer, index: index(), expected: expected as usize, found: found });
+    }
+    Ok(())
+}
+
+/// Packs the weights of a layer into the LayerWeights layout.
+pub fn pack_weights(layer: &Layer) -> Vec<Vec<f32>> {
+    let (kw, kh) = (layer.kW as usize, layer.kH as usize);
+    let mut w = Vec::with_capacity(layer.nInputPlane as usize);
+    for i in 0..layer.nInputPlane as usize {
+        let mut v = Vec::with_capacity(kw * kh * layer.nOutputPlane as usize);
+        for j in 0..layer.nOutputPlane as usize {
+            for y in 0..kh {
+                for x in 0..kw {
+                    v.push(layer.weight[j][i][y][x]);
+                }
+            }
+        }
+        w.push(v);
+    }
+    w
+}
+
+/// Inverse of pack_weights: the nested weight[out][in][y][x] of a layer.
+pub fn unpack_weights(layer: &Layer, packed: &[Vec<f32>]) -> Vec<Vec<Vec<Vec<f32>>>> {
+    let (kw, kh) = (layer.kW as usize, layer.kH as usize);
+    (0..layer.nOutputPlane as usize).map(|j| {
+        let off = j * kh * kw;
+        packed.iter().map(|p| {
+            (0..kh).map(|y| p[off + y * kw..off + (y + 1) * kw].to_vec()).collect()
+        }).collect()
+    }).collect()
+}
+
+// Binary model format, all values little-endian:
+//
+//   magic "W2XB", version (u32), number of layers (u32)
+//   per layer: nInputPlane, nOutputPlane, kW, kH (u32 each)
+//   per layer: bias (nOutputPlane f32), weights (nInputPlane x nOutputPlane x kH x kW f32
+//              in the LayerWeights layout)
+//
+// Every field is 4 bytes wide, so the f32 data is aligned within the file.
+const BINARY_MAGIC: &'static [u8; 4] = b"W2XB";
+const BINARY_VERSION: u32 = 1;
+
+/// Writes a model in the binary format read by load_binary_model.
+pub fn save_binary_model<P: AsRef<Path>>(model: &PackedModel, path: P) -> Result<(), LoadModelError> {
+    let f = match File::create(path) {
+        Ok(f) => f,
+        Err(e) => return Err(LoadModelError::IOError(e)),
+    };
+    let mut buf: Vec<u8> = Vec::new();
+    buf.extend_from_slice(BINARY_MAGIC);
+    push_u32(&mut buf, BINARY_VERSION);
+    push_u32(&mut buf, model.model.len() as u32);
+    for layer in model.model.iter() {
+        for &v in [layer.nInputPlane, layer.nOutputPlane, layer.kW, layer.kH].iter() {
+            push_u32(&mut buf, v);
+        }
+    }
+    for (layer, weights) in model.model.iter().zip(model.weights.iter()) {
+        for &v in layer.bias.iter() {
+            push_u32(&mut buf, v.to_bits());
+        }
+        for w in weights.iter() {
+            for &v in w.iter() {
+                push_u32(&mut buf, v.to_bits());
+            }
+        }
+    }
+    let mut out_strm = BufWriter::new(f);
+    match out_strm.write_all(&buf) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(LoadModelError::IOError(e)),
+    }
+}
+
+/// Loads a model written by save_binary_model. Only the packed weights are
+/// filled in (Layer::weight is left empty, see PackedModel::into_model); with
+/// the `memmap` feature the file is memory-mapped instead of read into a buffer.
+pub fn load_binary_model<P: AsRef<Path>>(path: P) -> Result<PackedModel, LoadModelError> {
+    let model = try!(with_model_bytes(path, parse_binary_model));
+    try!(validate_layers(&model.model));
+    Ok(model)
+}
+
+#[cfg(feature = "memmap")]
+fn with_model_bytes<P, F, T>(path: P, f: F) -> Result<T, LoadModelError>
+    where P: AsRef<Path>, F: FnOnce(&[u8]) -> Result<T, LoadModelError>
+{
+    use memmap::{Mmap, Protection};
+    let map = match Mmap::open_path(path, Protection::Read) {
+        Ok(m) => m,
+        Err(e) => return Err(LoadModelError::IOError(e)),
+    };
+    // the mapping is read-only and lives until f returns
+    f(unsafe { map.as_slice() })
+}
+
+#[cfg(not(feature = "memmap"))]
+fn with_model_bytes<P, F, T>(path: P, f: F) -> Result<T, LoadModelError>
+    where P: AsRef<Path>, F: FnOnce(&[u8]) -> Result<T, LoadModelError>
+{
+    let mut buf = Vec::new();
+    let read = File::open(path).and_then(|mut file| file.read_to_end(&mut buf));
+    match read {
+        Ok(_) => f(&buf),
+        Err(e) => Err(LoadModelError::IOError(e)),
+    }
+}
+
+fn parse_binary_model(data: &[u8]) -> Result<PackedModel, LoadModelError> {
+    let truncated = || LoadModelError::InvalidBinary("file is truncated".to_string());
+    if data.len() < 12 || &data[0..4] != BINARY_MAGIC {
+        return Err(LoadModelError::InvalidBinary("missing W2XB header".to_string()));
+    }
+    let version = read_u32(data, 4);
+    if version != BINARY_VERSION {
+        return Err(LoadModelError::InvalidBinary(format!("unsupported version {}", version)));
+    }
+    let num_layers = read_u32(data, 8) as usize;
+    let mut pos = 12;
+    if (data.len() - pos) / 16 < num_layers {
+        return Err(truncated());
+    }
+
+    let mut shapes = Vec::with_capacity(num_layers);
+    for _ in 0..num_layers {
+        shapes.push((read_u32(data, pos), read_u32(data, pos + 4),
+                     read_u32(data, pos + 8), read_u32(data, pos + 12)));
+        pos += 16;
+    }
+
+    let mut model = Vec::with_capacity(num_layers);
+    let mut weights = Vec::with_capacity(num_layers);
+    for &(num_in, num_out, kw, kh) in shapes.iter() {
+        let (num_in, num_out) = (num_in as usize, num_out as usize);
+        let (kw, kh) = (kw as usize, kh as usize);
+        let sizes = num_out.checked_mul(kh).and_then(|v| v.checked_mul(kw)).and_then(|per_plane| {
+            num_in.checked_mul(per_plane).and_then(|v| v.checked_add(num_out))
+                .and_then(|v| v.checked_mul(4)).map(|size| (per_plane, size))
+        });
+        let per_plane = match sizes {
+            Some((per_plane, size)) if size <= data.len() - pos => per_plane,
+            _ => return Err(truncated()),
+        };
+        let bias = read_f32s(data, pos, num_out);
+        pos += num_out * 4;
+        let mut packed = Vec::with_capacity(num_in);
+        for _ in 0..num_in {
+            packed.push(read_f32s(data, pos, per_plane));
+            pos += per_plane * 4;
+        }
+        model.push(Layer {
+            nInputPlane: num_in as u32,
+            nOutputPlane: num_out as u32,
+            kW: kw as u32,
+            kH: kh as u32,
+            bias: bias,
+            weight: Vec::new(),
+        });
+        weights.push(Arc::new(packed));
+    }
+    if pos != data.len() {
+        return Err(LoadModelError::InvalidBinary(
+            format!("{} trailing bytes", data.len() - pos)));
+    }
+    Ok(PackedModel {
+        model: model,
+        weights: weights,
+    })
+}
+
+fn push_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.push(v as u8);
+    buf.push((v >> 8) as u8);
+    buf.push((v >> 16) as u8);
+    buf.push((v >> 24) as u8);
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    data[pos] as u32 | (data[pos + 1] as u32) << 8 | (data[pos + 2] as u32) << 16 | (data[pos + 3] as u32) << 24
+}
+
+// the file is little-endian like the host: one copy straight into the vector
+#[cfg(target_endian = "little")]
+fn read_f32s(data: &[u8], pos: usize, count: usize) -> Vec<f32> {
+    let bytes = &data[pos..pos + count * 4];
+    let mut v: Vec<f32> = Vec::with_capacity(count);
+    unsafe {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), v.as_mut_ptr() as *mut u8, bytes.len());
+        v.set_len(count);
+    }
+    v
+}
+
+#[cfg(not(target_endian = "little"))]
+fn read_f32s(data: &[u8], pos: usize, count: usize) -> Vec<f32> {
+    (0..count).map(|i| f32::from_bits(read_u32(data, pos + i * 4))).collect()
+}
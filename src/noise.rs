@@ -0,0 +1,163 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use image::{ColorSpace, Image};
+
+/// IJG standard luminance quantization table (quality 50), in zigzag order
+/// like the tables stored in a DQT segment.
+pub const STD_LUMINANCE_ZIGZAG: [u16; 64] = [
+    16, 11, 12, 14, 12, 10, 16, 14, 13, 14, 18, 17, 16, 19, 24, 40,
+    26, 24, 22, 22, 24, 49, 35, 37, 29, 40, 58, 51, 61, 60, 57, 51,
+    56, 55, 64, 72, 92, 78, 64, 68, 87, 69, 55, 56, 80, 109, 81, 87,
+    95, 98, 103, 104, 103, 62, 77, 113, 121, 112, 100, 120, 92, 101, 103, 99,
+];
+
+/// Estimates the waifu2x noise level (0: light .. 3: heavy) of an image.
+/// JPEG files are judged by their quantization tables, everything else by
+/// the blockiness of the decoded image.
+pub fn estimate_level<P: AsRef<Path>>(path: Option<P>, img: &Image) -> u32 {
+    if let Some(quality) = path.and_then(|p| jpeg_quality(p)) {
+        return level_from_quality(quality);
+    }
+    level_from_blockiness(blockiness(img))
+}
+
+/// The element of `levels` closest to `level` (the lower one on ties).
+pub fn nearest_level(level: u32, levels: &[u32]) -> Option<u32> {
+    let distance = |l: u32| if l > level { l - level } else { level - l };
+    let mut best: Option<u32> = None;
+    for &l in levels.iter() {
+        best = match best {
+            Some(b) if distance(b) < distance(l) || (distance(b) == distance(l) && b < l) => Some(b),
+            _ => Some(l),
+        };
+    }
+    best
+}
+
+/// IJG quality (1-100) the luminance quantization table of a JPEG file was made with,
+/// None if the file is not a JPEG.
+pub fn jpeg_quality<P: AsRef<Path>>(path: P) -> Option<u32> {
+    let mut data = Vec::new();
+    if File::open(path).and_then(|mut f| f.read_to_end(&mut data)).is_err() {
+        return None;
+    }
+    let table = match luminance_table(&data) {
+        Some(t) => t,
+        None => return None,
+    };
+    // the tables are the standard table scaled by S percent
+    let mut sum = 0.0;
+    for i in 0..64 {
+        sum += table[i] as f64 * 100.0 / STD_LUMINANCE_ZIGZAG[i] as f64;
+    }
+    let scale = sum / 64.0;
+    let quality = if scale <= 100.0 { (200.0 - scale) / 2.0 } else { 5000.0 / scale };
+    Some(quality.round().max(1.0).min(100.0) as u32)
+}
+
+// first quantization table (Tq = 0) of a JPEG file
+fn luminance_table(data: &[u8]) -> Option<[u16; 64]> {
+    if data.len() < 4 || data[0] != 0xFF || data[1] != 0xD8 {
+        return None;
+    }
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            return None;
+        }
+        let marker = data[pos + 1];
+        let len = (data[pos + 2] as usize) << 8 | data[pos + 3] as usize;
+        // start of scan: no tables after this
+        if marker == 0xDA || len < 2 || pos + 2 + len > data.len() {
+            return None;
+        }
+        if marker == 0xDB {
+            let mut p = pos + 4;
+            let end = pos + 2 + len;
+            while p < end {
+                let precision = data[p] >> 4;
+                let id = data[p] & 0x0F;
+                let size = if precision == 0 { 64 } else { 128 };
+                if p + 1 + size > end {
+                    return None;
+                }
+                if id == 0 {
+                    let mut table = [0u16; 64];
+                    for i in 0..64 {
+                        table[i] = if precision == 0 {
+                            data[p + 1 + i] as u16
+                        } else {
+                            (data[p + 1 + i * 2] as u16) << 8 | data[p + 2 + i * 2] as u16
+                        };
+                    }
+                    return Some(table);
+                }
+                p += 1 + size;
+            }
+        }
+        pos += 2 + len;
+    }
+    None
+}
+
+/// Mean luma difference across 8x8 block boundaries divided by the mean
+/// difference between other neighbouring pixels. Around 1.0 for images without
+/// block artifacts, larger the stronger the JPEG compression was.
+pub fn blockiness(img: &Image) -> f32 {
+    if img.width < 16 || img.height < 16 {
+        return 1.0;
+    }
+    let luma = |x: usize, y: usize| -> f32 {
+        match img.color_space {
+            ColorSpace::RGB => {
+                let off = |k: usize| y * img.strides[k] + x;
+                0.299 * img.data[0][off(0)] + 0.587 * img.data[1][off(1)] + 0.114 * img.data[2][off(2)]
+            },
+            _ => img.data[0][y * img.strides[0] + x],
+        }
+    };
+
+    let (mut edge, mut edge_cnt) = (0.0f64, 0usize);
+    let (mut inner, mut inner_cnt) = (0.0f64, 0usize);
+    for y in 0..img.height {
+        for x in 0..img.width {
+            let v = luma(x, y);
+            if x + 1 < img.width {
+                let d = (luma(x + 1, y) - v).abs() as f64;
+                if x % 8 == 7 { edge += d; edge_cnt += 1; } else { inner += d; inner_cnt += 1; }
+            }
+            if y + 1 < img.height {
+                let d = (luma(x, y + 1) - v).abs() as f64;
+                if y % 8 == 7 { edge += d; edge_cnt += 1; } else { inner += d; inner_cnt += 1; }
+            }
+        }
+    }
+    let inner = inner / inner_cnt as f64;
+    if inner <= 0.0 {
+        return 1.0;
+    }
+    (edge / edge_cnt as f64 / inner) as f32
+}
+
+fn level_from_quality(quality: u32) -> u32 {
+    match quality {
+        90...100 => 0,
+        75...89 => 1,
+        50...74 => 2,
+        _ => 3,
+    }
+}
+
+fn level_from_blockiness(ratio: f32) -> u32 {
+    if ratio < 1.1 {
+        0
+    } else if ratio < 1.3 {
+        1
+    } else if ratio < 1.6 {
+        2
+    } else {
+        3
+    }
+}
@@ -0,0 +1,54 @@
+extern crate waifu2x_hsa;
+
+use std::env;
+use std::fs::{self, File};
+use std::path::PathBuf;
+
+use waifu2x_hsa::batch;
+
+fn test_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn literal_file_with_glob_characters() {
+    let dir = test_dir("waifu2x-hsa-test-glob-literal");
+    for name in ["scan[01].png", "scan0.png", "scan1.png"].iter() {
+        File::create(dir.join(name)).unwrap();
+    }
+    let arg = dir.join("scan[01].png").to_str().unwrap().to_string();
+    let items = batch::collect_inputs(&[&arg], false).unwrap();
+    let pattern = dir.join("scan[01].p?g").to_str().unwrap().to_string();
+    let matched = batch::collect_inputs(&[&pattern], false).unwrap();
+    let _ = fs::remove_dir_all(&dir);
+
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].input, dir.join("scan[01].png"));
+    assert_eq!(items[0].relative, PathBuf::from("scan[01].png"));
+    // a path which does not exist is still expanded as a pattern
+    let names: Vec<_> = matched.iter().map(|i| i.relative.clone()).collect();
+    assert_eq!(names, vec![PathBuf::from("scan0.png"), PathBuf::from("scan1.png")]);
+}
+
+#[test]
+fn tiff_inputs_are_written_as_png() {
+    let item = batch::BatchItem {
+        input: PathBuf::from("scans/a/page.tif"),
+        relative: PathBuf::from("a/page.tif"),
+    };
+    let jpeg = batch::BatchItem {
+        input: PathBuf::from("scans/photo.jpg"),
+        relative: PathBuf::from("photo.jpg"),
+    };
+    let out = batch::output_path("out", batch::DEFAULT_TEMPLATE, &item);
+    assert_eq!(out, PathBuf::from("out/a/page.png"));
+    assert_eq!(batch::output_path("out", batch::DEFAULT_TEMPLATE, &jpeg), PathBuf::from("out/photo.jpg"));
+
+    let jobs = batch::plan_jobs(vec![item.clone(), jpeg.clone()], "out", batch::DEFAULT_TEMPLATE).unwrap();
+    assert_eq!(jobs.len(), 2);
+    // an explicit TIFF template is rejected before any job runs
+    assert!(batch::plan_jobs(vec![jpeg, item], "out", "{stem}.tiff").is_err());
+}
@@ -0,0 +1,350 @@
+extern crate waifu2x_hsa;
+
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use waifu2x_hsa::{cnn, Error, PerfStatus, UpscalerBuilder};
+use waifu2x_hsa::cnn::WinogradTile;
+use waifu2x_hsa::image::{Image, ColorSpace};
+use waifu2x_hsa::model::{self, Model, Layer, LayerWeights, PackedModel};
+
+const TOLERANCE: f32 = 1e-4;
+
+// small deterministic LCG so the tests do not need an RNG crate
+struct Rng(u64);
+
+impl Rng {
+    fn next_u32(&mut self) -> u32 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.0 >> 33) as u32
+    }
+
+    // uniform in [0, 1)
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+
+    // uniform in [lo, hi]
+    fn range(&mut self, lo: u32, hi: u32) -> u32 {
+        lo + self.next_u32() % (hi - lo + 1)
+    }
+}
+
+fn random_layer(rng: &mut Rng, num_in: u32, num_out: u32, kw: u32, kh: u32) -> Layer {
+    // keep activations around 1.0 regardless of the fan-in
+    let scale = 2.0 / ((num_in * kw * kh) as f32).sqrt();
+    let mut weight = Vec::with_capacity(num_out as usize);
+    for _ in 0..num_out {
+        let mut w_o = Vec::with_capacity(num_in as usize);
+        for _ in 0..num_in {
+            let mut w_oi = Vec::with_capacity(kh as usize);
+            for _ in 0..kh {
+                let mut row = Vec::with_capacity(kw as usize);
+                for _ in 0..kw {
+                    row.push((rng.next_f32() - 0.5) * scale);
+                }
+                w_oi.push(row);
+            }
+            w_o.push(w_oi);
+        }
+        weight.push(w_o);
+    }
+    let bias = (0..num_out).map(|_| (rng.next_f32() - 0.5) * 0.2).collect();
+    Layer {
+        nInputPlane: num_in,
+        nOutputPlane: num_out,
+        kW: kw,
+        kH: kh,
+        bias: bias,
+        weight: weight,
+    }
+}
+
+// planes[i] -> planes[i + 1] for every layer, all kernels 3x3
+fn random_model(rng: &mut Rng, planes: &[u32]) -> Model {
+    let kernels: Vec<(u32, u32)> = (1..planes.len()).map(|_| (3, 3)).collect();
+    random_model_with_kernels(rng, planes, &kernels)
+}
+
+fn random_model_with_kernels(rng: &mut Rng, planes: &[u32], kernels: &[(u32, u32)]) -> Model {
+    let mut model = Vec::new();
+    for i in 0..planes.len() - 1 {
+        let (kw, kh) = kernels[i];
+        model.push(random_layer(rng, planes[i], planes[i + 1], kw, kh));
+    }
+    model::validate_model(&model).unwrap();
+    model
+}
+
+fn random_image(rng: &mut Rng, width: usize, height: usize, channels: usize) -> Image {
+    let mut img = Image::new(width, height, channels, ColorSpace::RGB);
+    for plane in img.data.iter_mut() {
+        for v in plane.iter_mut() {
+            *v = rng.next_f32();
+        }
+    }
+    img
+}
+
+fn max_abs_diff(a: &Image, b: &Image) -> f32 {
+    assert_eq!((a.width, a.height), (b.width, b.height));
+    assert_eq!(a.data.len(), b.data.len());
+    let mut max = 0.0f32;
+    for k in 0..a.data.len() {
+        for y in 0..a.height {
+            for x in 0..a.width {
+                let d = (a.data[k][y * a.strides[k] + x] - b.data[k][y * b.strides[k] + x]).abs();
+                if d > max {
+                    max = d;
+                }
+            }
+        }
+    }
+    max
+}
+
+fn check_equivalence(model: &Model, img: &Image) {
+    let (pad_x, pad_y) = model::padding(model);
+    let out1 = cnn::filter_cpu1(img.clone(), model, &mut PerfStatus::new());
+    let out2 = cnn::filter_cpu2(img.clone(), model, &mut PerfStatus::new());
+    assert_eq!((out1.width, out1.height), (img.width - pad_x * 2, img.height - pad_y * 2));
+
+    let diff = max_abs_diff(&out1, &out2);
+    assert!(diff <= TOLERANCE, "cpu1/cpu2 differ by {} ({}x{}, {} layers)",
+            diff, img.width, img.height, model.len());
+
+    for threads in 2..5 {
+        let out_mt = cnn::filter_cpu2_mt(img.clone(), model, threads, &mut PerfStatus::new());
+        assert_eq!(max_abs_diff(&out2, &out_mt), 0.0,
+                   "filter_cpu2_mt({}) is not bit-identical", threads);
+    }
+
+    let weights: Vec<_> = model.iter().map(|l| Arc::new(cnn::gemm_pack_weights(l))).collect();
+    let out_gemm = cnn::filter_gemm(img.clone(), model, &weights, 1, &mut PerfStatus::new());
+    let diff = max_abs_diff(&out1, &out_gemm);
+    assert!(diff <= TOLERANCE, "cpu1/gemm differ by {} ({}x{}, {} layers)",
+            diff, img.width, img.height, model.len());
+    let out_gemm_mt = cnn::filter_gemm(img.clone(), model, &weights, 3, &mut PerfStatus::new());
+    assert_eq!(max_abs_diff(&out_gemm, &out_gemm_mt), 0.0, "filter_gemm(3) is not bit-identical");
+
+    for tile in [WinogradTile::F2x2, WinogradTile::F4x4].iter() {
+        let weights: Vec<_> = model.iter().map(|l| Arc::new(cnn::winograd_pack_weights(l, *tile))).collect();
+        let out_wino = cnn::filter_winograd(img.clone(), model, &weights, 1, &mut PerfStatus::new());
+        let diff = max_abs_diff(&out1, &out_wino);
+        assert!(diff <= TOLERANCE, "cpu1/winograd {:?} differ by {} ({}x{}, {} layers)",
+                tile, diff, img.width, img.height, model.len());
+        let out_wino_mt = cnn::filter_winograd(img.clone(), model, &weights, 3, &mut PerfStatus::new());
+        assert_eq!(max_abs_diff(&out_wino, &out_wino_mt), 0.0,
+                   "filter_winograd({:?}, 3) is not bit-identical", tile);
+    }
+}
+
+#[test]
+fn random_models() {
+    let mut rng = Rng(1);
+    for _ in 0..20 {
+        let depth = rng.range(1, 4) as usize;
+        let io = if rng.range(0, 1) == 0 { 1 } else { 3 };
+        let mut planes = vec![io];
+        for _ in 1..depth {
+            planes.push(rng.range(1, 40));
+        }
+        planes.push(io);
+        let model = random_model(&mut rng, &planes);
+        let width = depth * 2 + rng.range(1, 12) as usize;
+        let height = depth * 2 + rng.range(1, 12) as usize;
+        let img = random_image(&mut rng, width, height, io as usize);
+        check_equivalence(&model, &img);
+    }
+}
+
+#[test]
+fn odd_widths() {
+    let mut rng = Rng(2);
+    let model = random_model(&mut rng, &[3, 16, 32, 3]);
+    for width in [7, 9, 13, 31].iter() {
+        let img = random_image(&mut rng, *width, 10, 3);
+        check_equivalence(&model, &img);
+    }
+}
+
+#[test]
+fn height_one_after_shrink() {
+    let mut rng = Rng(3);
+    let model = random_model(&mut rng, &[1, 8, 32, 16, 1]);
+    let img = random_image(&mut rng, 15, model.len() * 2 + 1, 1);
+    check_equivalence(&model, &img);
+
+    let img = random_image(&mut rng, model.len() * 2 + 1, 11, 1);
+    check_equivalence(&model, &img);
+}
+
+#[test]
+fn plane_counts_not_multiple_of_8() {
+    let mut rng = Rng(4);
+    let model = random_model(&mut rng, &[1, 33, 47, 35, 1]);
+    let img = random_image(&mut rng, 12, 9, 1);
+    check_equivalence(&model, &img);
+}
+
+#[test]
+fn more_than_128_planes() {
+    let mut rng = Rng(5);
+    let model = random_model(&mut rng, &[3, 130, 3]);
+    let img = random_image(&mut rng, 8, 6, 3);
+    check_equivalence(&model, &img);
+}
+
+#[test]
+fn kernel_sizes() {
+    let mut rng = Rng(6);
+    let model = random_model_with_kernels(&mut rng, &[1, 8, 40, 16, 1],
+                                          &[(1, 1), (5, 5), (3, 5), (3, 3)]);
+    assert_eq!(model::padding(&model), (4, 5));
+    let img = random_image(&mut rng, 13, 14, 1);
+    check_equivalence(&model, &img);
+}
+
+#[test]
+fn gemm_several_bands() {
+    // wide enough for several im2col bands per layer, K = 9 * 32 spans two K blocks
+    let mut rng = Rng(8);
+    let model = random_model(&mut rng, &[1, 32, 37, 1]);
+    let img = random_image(&mut rng, 101, 17, 1);
+    check_equivalence(&model, &img);
+}
+
+#[test]
+fn gemm_wide_rows() {
+    // rows wider than an im2col block are lowered in several blocks
+    let mut rng = Rng(14);
+    let model = random_model_with_kernels(&mut rng, &[1, 8, 24, 1], &[(3, 3), (1, 1), (5, 3)]);
+    let img = random_image(&mut rng, 613, 7, 1);
+    check_equivalence(&model, &img);
+}
+
+#[test]
+fn binary_model_roundtrip() {
+    let mut rng = Rng(7);
+    let model = random_model_with_kernels(&mut rng, &[3, 9, 33, 3], &[(3, 3), (1, 1), (5, 3)]);
+    let path = env::temp_dir().join("waifu2x-hsa-test-model.bin");
+    model::save_binary_model(&PackedModel::new(model.clone()), &path).unwrap();
+    let loaded = model::load_packed_model(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+
+    assert!(loaded.model.iter().all(|l| l.weight.is_empty()));
+    for (a, b) in model.iter().zip(loaded.clone().into_model().iter()) {
+        assert_eq!((a.nInputPlane, a.nOutputPlane, a.kW, a.kH), (b.nInputPlane, b.nOutputPlane, b.kW, b.kH));
+        assert_eq!(a.bias, b.bias);
+        assert_eq!(a.weight, b.weight);
+    }
+    let img = random_image(&mut rng, 11, 9, 3);
+    let out = cnn::filter_cpu2(img.clone(), &model, &mut PerfStatus::new());
+    let out_packed = cnn::filter_cpu2_packed(img.clone(), &loaded.model, &loaded.weights, &mut PerfStatus::new());
+    assert_eq!(max_abs_diff(&out, &out_packed), 0.0);
+}
+
+#[test]
+#[should_panic(expected = "invalid model")]
+fn short_bias_rejected() {
+    let mut rng = Rng(9);
+    let mut model = random_model(&mut rng, &[1, 4, 1]);
+    model[0].bias.pop();
+    let img = random_image(&mut rng, 8, 8, 1);
+    cnn::filter_cpu2(img, &model, &mut PerfStatus::new());
+}
+
+#[test]
+#[should_panic(expected = "the model crops off")]
+fn image_smaller_than_padding_rejected() {
+    let mut rng = Rng(10);
+    let model = random_model(&mut rng, &[1, 4, 4, 1]);
+    let weights: Vec<_> = model.iter().map(|l| Arc::new(cnn::gemm_pack_weights(l))).collect();
+    let img = random_image(&mut rng, 6, 3, 1);
+    cnn::filter_gemm(img, &model, &weights, 1, &mut PerfStatus::new());
+}
+
+#[test]
+#[should_panic(expected = "packed weights do not match")]
+fn mismatched_weights_rejected() {
+    let mut rng = Rng(11);
+    let model = random_model(&mut rng, &[1, 4, 1]);
+    let other = random_model(&mut rng, &[1, 8, 1]);
+    let weights: Vec<LayerWeights> = other.iter().map(|l| Arc::new(model::pack_weights(l))).collect();
+    let img = random_image(&mut rng, 8, 8, 1);
+    cnn::filter_cpu2_packed(img, &model, &weights, &mut PerfStatus::new());
+}
+
+// a model directory with a random Y scale2.0x model
+fn random_model_dir(rng: &mut Rng, name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let model = random_model_with_kernels(rng, &[1, 8, 16, 1], &[(3, 3), (5, 5), (3, 3)]);
+    model::save_binary_model(&PackedModel::new(model), dir.join("scale2.0x_model.bin")).unwrap();
+    dir
+}
+
+#[test]
+fn tiled_matches_untiled() {
+    let mut rng = Rng(12);
+    let dir = random_model_dir(&mut rng, "waifu2x-hsa-test-tiles");
+    let mut img = random_image(&mut rng, 23, 17, 1);
+    img.color_space = ColorSpace::Gray;
+    for &threads in [1, 3].iter() {
+        let whole = UpscalerBuilder::new(&dir).backend("cpu2").threads(threads).build().unwrap();
+        let tiled = UpscalerBuilder::new(&dir).backend("cpu2").threads(threads).tile_size(Some(7)).build().unwrap();
+        let a = whole.upscale(img.clone()).unwrap().image;
+        let b = tiled.upscale(img.clone()).unwrap().image;
+        assert_eq!(max_abs_diff(&a, &b), 0.0);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn memory_budget_below_one_tile_rejected() {
+    let mut rng = Rng(13);
+    let dir = random_model_dir(&mut rng, "waifu2x-hsa-test-budget");
+    let small = UpscalerBuilder::new(&dir).backend("cpu2").max_memory(Some(64)).build();
+    let enough = UpscalerBuilder::new(&dir).backend("cpu2").max_memory(Some(1 << 20)).build();
+    let _ = std::fs::remove_dir_all(&dir);
+    match small {
+        Err(Error::InvalidArgument(msg)) => assert!(msg.contains("at least"), "{}", msg),
+        _ => panic!("a 64 byte budget was accepted"),
+    }
+    assert!(enough.is_ok());
+}
+
+#[test]
+fn layer_flops_large_planes() {
+    // 70000 * 70000 * 3 * 3 overflows u32
+    let layer = Layer { nInputPlane: 70000, nOutputPlane: 70000, kW: 3, kH: 3, bias: Vec::new(), weight: Vec::new() };
+    assert_eq!(cnn::layer_flops(&layer, 2, 1), (70000u64 * 70000 * 9 * 2 + 70000) * 2);
+    let huge = Layer { nInputPlane: !0, nOutputPlane: !0, kW: 3, kH: 3, bias: Vec::new(), weight: Vec::new() };
+    assert_eq!(cnn::layer_flops(&huge, 1, 1), !0u64);
+}
+
+#[test]
+fn scale_plan() {
+    let mut rng = Rng(15);
+    let dir = random_model_dir(&mut rng, "waifu2x-hsa-test-scale-plan");
+    let mut img = random_image(&mut rng, 23, 17, 1);
+    img.color_space = ColorSpace::Gray;
+    let run = |builder: UpscalerBuilder| {
+        let out = builder.backend("cpu2").build().unwrap().upscale(img.clone()).unwrap();
+        (out.image.width, out.image.height, out.perf.cnn_flo)
+    };
+    let (_, _, one_pass) = run(UpscalerBuilder::new(&dir).scale(2.0));
+    let (_, _, two_passes) = run(UpscalerBuilder::new(&dir).scale(4.0));
+    assert!(one_pass > 0 && two_passes > one_pass * 4);
+
+    // 3x: two 2x passes, then shrunk to size
+    assert_eq!(run(UpscalerBuilder::new(&dir).scale(3.0)), (69, 51, two_passes));
+    assert_eq!(run(UpscalerBuilder::new(&dir).scale(4.0)), (92, 68, two_passes));
+    // --width only keeps the aspect ratio
+    assert_eq!(run(UpscalerBuilder::new(&dir).width(Some(40))), (40, 30, one_pass));
+    // below 1x the CNN is not run
+    assert_eq!(run(UpscalerBuilder::new(&dir).scale(0.5)), (12, 9, 0));
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,280 @@
+extern crate waifu2x_hsa;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use waifu2x_hsa::image::{self, Image, ColorSpace, Interpolation, PaddingMode, SampleFormat};
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("waifu2x-hsa-test-{}", name))
+}
+
+fn write_file(name: &str, data: &[u8]) -> PathBuf {
+    let path = temp_path(name);
+    File::create(&path).and_then(|mut f| f.write_all(data)).unwrap();
+    path
+}
+
+fn read_file(path: &PathBuf) -> Vec<u8> {
+    let mut data = Vec::new();
+    File::open(path).and_then(|mut f| f.read_to_end(&mut data)).unwrap();
+    data
+}
+
+// loads `data` saved as `name`, removing the file again
+fn load_bytes(name: &str, data: &[u8]) -> waifu2x_hsa::Result<Image> {
+    let path = write_file(name, data);
+    let img = image::load(&path);
+    let _ = fs::remove_file(&path);
+    img
+}
+
+fn ramp_image(width: usize, height: usize, channels: usize, color_space: ColorSpace, max: f32) -> Image {
+    let mut img = Image::new(width, height, channels, color_space);
+    for k in 0..channels {
+        for (i, v) in img.data[k].iter_mut().enumerate() {
+            *v = ((i * 7 + k * 13) % (max as usize + 1)) as f32 / max;
+        }
+    }
+    img
+}
+
+fn assert_same_pixels(a: &Image, b: &Image) {
+    assert_eq!((a.width, a.height, a.data.len()), (b.width, b.height, b.data.len()));
+    for k in 0..a.data.len() {
+        for y in 0..a.height {
+            for x in 0..a.width {
+                assert_eq!(a.data[k][y * a.strides[k] + x], b.data[k][y * b.strides[k] + x]);
+            }
+        }
+    }
+}
+
+#[test]
+fn pfm_roundtrip() {
+    let mut rgb = ramp_image(5, 3, 3, ColorSpace::RGB, 255.0);
+    rgb.data[0][0] = -0.25;
+    rgb.data[2][14] = 3.5;
+    let gray = ramp_image(4, 6, 1, ColorSpace::Gray, 255.0);
+    for &(name, ref img) in [("rt-rgb.pfm", &rgb), ("rt-gray.pfm", &gray)].iter() {
+        let path = temp_path(name);
+        image::save(img, &path).unwrap();
+        let loaded = image::load(&path).unwrap();
+        let data = read_file(&path);
+        let _ = fs::remove_file(&path);
+        assert_eq!(loaded.sample_format, SampleFormat::F32);
+        assert!(loaded.color_space == img.color_space);
+        assert_same_pixels(img, &loaded);
+
+        // negative scale (little-endian), bottom row first
+        let header = format!("{}\n{} {}\n-1.0\n", if img.data.len() == 1 { "Pf" } else { "PF" },
+                             img.width, img.height);
+        assert_eq!(&data[..header.len()], header.as_bytes());
+        let b = &data[header.len()..header.len() + 4];
+        let first = (b[3] as u32) << 24 | (b[2] as u32) << 16 | (b[1] as u32) << 8 | b[0] as u32;
+        assert_eq!(f32::from_bits(first), img.data[0][(img.height - 1) * img.width]);
+    }
+}
+
+#[test]
+fn pfm_big_endian() {
+    // positive scale: big-endian samples, rows stored bottom-up
+    let mut data = b"Pf\n2 2\n1.0\n".to_vec();
+    for &v in [3.0f32, 4.0, 1.0, 2.0].iter() {
+        let bits = v.to_bits();
+        data.extend_from_slice(&[(bits >> 24) as u8, (bits >> 16) as u8, (bits >> 8) as u8, bits as u8]);
+    }
+    let img = load_bytes("be.pfm", &data).unwrap();
+    assert_eq!(img.data[0], vec![1.0, 2.0, 3.0, 4.0]);
+}
+
+#[test]
+fn pfm_malformed() {
+    assert!(load_bytes("bad-magic.pfm", b"P6\n1 1\n-1.0\n\0\0\0\0").is_err());
+    assert!(load_bytes("short-header.pfm", b"PF\n2").is_err());
+    assert!(load_bytes("bad-size.pfm", b"Pf\nx 1\n-1.0\n\0\0\0\0").is_err());
+    assert!(load_bytes("truncated.pfm", b"Pf\n2 2\n-1.0\n\0\0\0\0\0\0\0\0").is_err());
+    assert!(load_bytes("huge.pfm", b"PF\n18446744073709551615 3\n-1.0\n").is_err());
+}
+
+#[test]
+fn png16_roundtrip() {
+    for &(channels, ref color_space) in [(1, ColorSpace::Gray), (4, ColorSpace::RGB)].iter() {
+        let mut img = ramp_image(7, 5, channels, color_space.clone(), 65535.0);
+        img.has_alpha = channels == 4;
+        img.sample_format = SampleFormat::U16;
+        let path = temp_path(&format!("rt16-{}.png", channels));
+        image::save(&img, &path).unwrap();
+        let loaded = image::load(&path).unwrap();
+        let data = read_file(&path);
+        let _ = fs::remove_file(&path);
+        assert_eq!(data[24], 16);
+        assert_eq!(loaded.sample_format, SampleFormat::U16);
+        assert_eq!(loaded.has_alpha, img.has_alpha);
+        assert!(loaded.color_space == *color_space);
+        assert_same_pixels(&img, &loaded);
+
+        assert!(load_bytes(&format!("truncated16-{}.png", channels), &data[..data.len() / 2]).is_err());
+    }
+}
+
+// uncompressed single strip TIFF
+fn tiff(le: bool, width: u32, height: u32, bits: u32, samples: u32, photometric: u32, pixels: &[u8]) -> Vec<u8> {
+    let put = |buf: &mut Vec<u8>, v: u32, size: usize| {
+        for i in 0..size {
+            let shift = if le { i } else { size - 1 - i } * 8;
+            buf.push((v >> shift) as u8);
+        }
+    };
+    let entries = [(256, 4, width), (257, 4, height), (258, 3, bits), (259, 3, 1), (262, 3, photometric),
+                   (273, 4, 8 + 2 + 8 * 12 + 4), (277, 3, samples), (279, 4, pixels.len() as u32)];
+    let mut buf = if le { b"II*\0".to_vec() } else { b"MM\0*".to_vec() };
+    put(&mut buf, 8, 4);
+    put(&mut buf, entries.len() as u32, 2);
+    for &(tag, typ, value) in entries.iter() {
+        put(&mut buf, tag, 2);
+        put(&mut buf, typ, 2);
+        put(&mut buf, 1, 4);
+        if typ == 3 {
+            put(&mut buf, value, 2);
+            put(&mut buf, 0, 2);
+        } else {
+            put(&mut buf, value, 4);
+        }
+    }
+    put(&mut buf, 0, 4);
+    buf.extend_from_slice(pixels);
+    buf
+}
+
+#[test]
+fn tiff_uncompressed() {
+    let values = [0u32, 1000, 65535, 30000, 2, 40000];
+    for &le in [true, false].iter() {
+        let mut pixels = Vec::new();
+        for &v in values.iter() {
+            if le { pixels.extend_from_slice(&[v as u8, (v >> 8) as u8]); }
+            else { pixels.extend_from_slice(&[(v >> 8) as u8, v as u8]); }
+        }
+        let img = load_bytes("rgb16.tif", &tiff(le, 2, 1, 16, 3, 2, &pixels)).unwrap();
+        assert_eq!(img.sample_format, SampleFormat::U16);
+        assert!(img.color_space == ColorSpace::RGB);
+        for x in 0..2 {
+            for k in 0..3 {
+                assert_eq!(img.data[k][x], values[x * 3 + k] as f32 / 65535.0);
+            }
+        }
+    }
+
+    // 8-bit WhiteIsZero
+    let img = load_bytes("gray8.tif", &tiff(false, 2, 2, 8, 1, 0, &[0, 255, 51, 204])).unwrap();
+    assert_eq!(img.sample_format, SampleFormat::U8);
+    assert!(img.color_space == ColorSpace::Gray);
+    assert_eq!(img.data[0], vec![1.0, 0.0, 1.0 - 51.0 / 255.0, 1.0 - 204.0 / 255.0]);
+}
+
+#[test]
+fn tiff_malformed() {
+    let good = tiff(true, 2, 2, 8, 1, 1, &[1, 2, 3, 4]);
+    assert!(load_bytes("ok.tif", &good).is_ok());
+
+    assert!(load_bytes("bad-header.tif", b"II+\0\x08\0\0\0").is_err());
+    assert!(load_bytes("empty.tif", b"").is_err());
+    // IFD offset past the end of the file
+    assert!(load_bytes("bad-ifd.tif", b"II*\0\xff\0\0\0").is_err());
+    // IFD cut off in the middle of an entry
+    assert!(load_bytes("cut-ifd.tif", &good[..30]).is_err());
+    // strip data cut off
+    assert!(load_bytes("cut-strip.tif", &good[..good.len() - 1]).is_err());
+    // strip shorter than the declared size
+    assert!(load_bytes("short-strip.tif", &tiff(true, 4, 4, 8, 1, 1, &[1, 2, 3, 4])).is_err());
+    // unsupported bit depth
+    assert!(load_bytes("bits4.tif", &tiff(true, 2, 2, 4, 1, 1, &[1, 2])).is_err());
+}
+
+// pads a row (or, transposed, a column) of `len` pixels with values 1..len by `n`
+// on both sides and returns the padded line
+fn padded_line(mode: PaddingMode, len: usize, n: usize, vertical: bool) -> Vec<f32> {
+    let (w, h) = if vertical { (1, len) } else { (len, 1) };
+    let mut img = Image::new(w, h, 1, ColorSpace::Gray);
+    for i in 0..len {
+        img.data[0][i] = (i + 1) as f32;
+    }
+    let (pad_x, pad_y) = if vertical { (0, n) } else { (n, 0) };
+    img.add_padding(pad_x, pad_y, mode).data[0].clone()
+}
+
+// `left` and `right` are the source indices of the padding, None for zeros
+fn check_padding(mode: PaddingMode, len: usize, left: &[Option<usize>], right: &[Option<usize>]) {
+    let value = |i: &Option<usize>| i.map_or(0.0, |i| (i + 1) as f32);
+    let mut expected: Vec<f32> = left.iter().map(&value).collect();
+    expected.extend((1..len + 1).map(|v| v as f32));
+    expected.extend(right.iter().map(&value));
+    for &vertical in [false, true].iter() {
+        assert_eq!(padded_line(mode, len, left.len(), vertical), expected, "{:?} n={}", mode, left.len());
+    }
+}
+
+#[test]
+fn padding_modes() {
+    let (a, b, c, d) = (Some(0), Some(1), Some(2), Some(3));
+    // aa|abcd|dd
+    check_padding(PaddingMode::Replicate, 4, &[a], &[d]);
+    check_padding(PaddingMode::Replicate, 4, &[a, a], &[d, d]);
+    // ba|abcd|dc
+    check_padding(PaddingMode::Reflect, 4, &[a], &[d]);
+    check_padding(PaddingMode::Reflect, 4, &[b, a], &[d, c]);
+    check_padding(PaddingMode::Reflect, 4, &[d, d, c, b, a], &[d, c, b, a, a]);
+    // cb|abcd|cb
+    check_padding(PaddingMode::Mirror101, 4, &[b], &[c]);
+    check_padding(PaddingMode::Mirror101, 4, &[c, b], &[c, b]);
+    check_padding(PaddingMode::Mirror101, 4, &[b, c, d, c, b], &[c, b, a, b, c]);
+    check_padding(PaddingMode::Mirror101, 1, &[a, a], &[a, a]);
+    // 00|abcd|00
+    check_padding(PaddingMode::Zero, 4, &[None], &[None]);
+    check_padding(PaddingMode::Zero, 4, &[None, None], &[None, None]);
+    // cd|abcd|ab
+    check_padding(PaddingMode::Wrap, 4, &[d], &[a]);
+    check_padding(PaddingMode::Wrap, 4, &[c, d], &[a, b]);
+}
+
+#[test]
+fn tta_transforms_invert() {
+    let mut i444 = ramp_image(5, 3, 4, ColorSpace::I444, 255.0);
+    i444.has_alpha = true;
+    let mut gray = ramp_image(2, 7, 2, ColorSpace::Gray, 255.0);
+    gray.has_alpha = true;
+    for img in [i444, gray].iter() {
+        for t in 0..8 {
+            let transformed = waifu2x_hsa::tta_transform(img, t);
+            if t & 4 != 0 {
+                assert_eq!((transformed.width, transformed.height), (img.height, img.width));
+            }
+            let restored = waifu2x_hsa::tta_inverse(&transformed, t);
+            assert!(restored.color_space == img.color_space);
+            assert_eq!(restored.has_alpha, img.has_alpha);
+            assert_same_pixels(img, &restored);
+        }
+    }
+}
+
+#[test]
+fn resize_weights_normalized() {
+    // a flat image stays flat up to the edges when every output's weights sum to 1
+    let mut img = Image::new(7, 5, 1, ColorSpace::Gray);
+    for v in img.data[0].iter_mut() {
+        *v = 0.7;
+    }
+    for &filter in [Interpolation::Nearest, Interpolation::Bilinear, Interpolation::Bicubic,
+                    Interpolation::Lanczos3].iter() {
+        for &(w, h) in [(16, 3), (3, 11), (7, 5), (1, 1)].iter() {
+            let out = img.resize(w, h, filter);
+            assert_eq!((out.width, out.height), (w, h));
+            for &v in out.data[0].iter() {
+                assert!((v - 0.7).abs() < 1e-5, "{:?} {}x{}: {}", filter, w, h, v);
+            }
+        }
+    }
+}
@@ -0,0 +1,80 @@
+extern crate waifu2x_hsa;
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
+use waifu2x_hsa::noise;
+use waifu2x_hsa::image::{Image, ColorSpace};
+
+// SOI, a DQT with the standard table scaled like libjpeg's jpeg_set_quality, SOS
+fn write_jpeg_header(name: &str, quality: u32) -> PathBuf {
+    let scale = if quality < 50 { 5000 / quality } else { 200 - quality * 2 };
+    let mut data = vec![0xFF, 0xD8, 0xFF, 0xDB, 0x00, 67, 0x00];
+    for &q in noise::STD_LUMINANCE_ZIGZAG.iter() {
+        let v = (q as u32 * scale + 50) / 100;
+        data.push(std::cmp::max(1, std::cmp::min(255, v)) as u8);
+    }
+    data.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x02]);
+    let path = env::temp_dir().join(name);
+    File::create(&path).and_then(|mut f| f.write_all(&data)).unwrap();
+    path
+}
+
+// gray image whose neighbour differences are 1 inside 8x8 blocks and
+// `ratio` across block boundaries, so blockiness() == ratio
+fn blocky_image(ratio: f32) -> Image {
+    let mut img = Image::new(32, 32, 1, ColorSpace::Gray);
+    let mut ramp = vec![0.0f32; 32];
+    for x in 1..32 {
+        ramp[x] = ramp[x - 1] + if x % 8 == 0 { ratio } else { 1.0 } * 0.001;
+    }
+    for y in 0..32 {
+        for x in 0..32 {
+            img.data[0][y * img.strides[0] + x] = ramp[x] + ramp[y];
+        }
+    }
+    img
+}
+
+#[test]
+fn jpeg_quality_levels() {
+    let flat = Image::new(32, 32, 1, ColorSpace::Gray);
+    for &(quality, level) in [(50, 2), (75, 1), (90, 0)].iter() {
+        let path = write_jpeg_header(&format!("waifu2x-hsa-test-q{}.jpg", quality), quality);
+        let found = noise::jpeg_quality(&path);
+        let estimated = noise::estimate_level(Some(&path), &flat);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(found, Some(quality));
+        assert_eq!(estimated, level);
+    }
+}
+
+#[test]
+fn jpeg_quality_not_jpeg() {
+    let path = env::temp_dir().join("waifu2x-hsa-test-not-jpeg.jpg");
+    File::create(&path).and_then(|mut f| f.write_all(b"\x89PNG\r\n\x1a\n")).unwrap();
+    let found = noise::jpeg_quality(&path);
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(found, None);
+}
+
+#[test]
+fn nearest_level_ties_and_empty() {
+    assert_eq!(noise::nearest_level(1, &[0, 2]), Some(0));
+    assert_eq!(noise::nearest_level(1, &[2, 0]), Some(0));
+    assert_eq!(noise::nearest_level(3, &[1, 2]), Some(2));
+    assert_eq!(noise::nearest_level(0, &[1, 3]), Some(1));
+    assert_eq!(noise::nearest_level(2, &[]), None);
+}
+
+#[test]
+fn blockiness_thresholds() {
+    for &(ratio, level) in [(1.0, 0), (1.09, 0), (1.11, 1), (1.29, 1), (1.31, 2), (1.59, 2),
+                            (1.61, 3), (3.0, 3)].iter() {
+        let img = blocky_image(ratio);
+        assert!((noise::blockiness(&img) - ratio).abs() < 1e-3);
+        assert_eq!(noise::estimate_level(None::<&str>, &img), level);
+    }
+}